@@ -0,0 +1,293 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdout, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::{HeaderMap, Request, Version};
+use serde::Serialize;
+use tracing::error;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+// Longest X-Request-ID taken from a client, anything else gets a generated id
+const MAX_REQUEST_ID_LEN: usize = 128;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Combined log format followed by the duration in seconds, worker and request id
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where finished requests are written, stdout or a file that can be reopened after it was
+/// rotated.
+pub struct AccessLog {
+    format: Format,
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    /// Opens `destination`, "-" meaning stdout.
+    pub fn open(format: Format, destination: &str) -> io::Result<Self> {
+        let path = (destination != "-").then(|| PathBuf::from(destination));
+        let file = path.as_ref().map(|path| append(path)).transpose()?;
+
+        Ok(Self {
+            format,
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Opens the log file again, for log rotation.
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            *self.file.lock().unwrap() = Some(append(path)?);
+        }
+
+        Ok(())
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let mut line = match self.format {
+            Format::Combined => entry.combined(),
+            Format::Json => entry.json(),
+        };
+        line.push('\n');
+
+        let written = match &mut *self.file.lock().unwrap() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => stdout().lock().write_all(line.as_bytes()),
+        };
+
+        if let Err(err) = written {
+            error!(error = %err, "Failed to write the access log");
+        }
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// One request in the access log, created when it arrives and completed with the response.
+#[derive(Serialize)]
+pub struct Entry {
+    #[serde(serialize_with = "serialize_time")]
+    time: SystemTime,
+    request_id: String,
+    client: Option<SocketAddr>,
+    method: String,
+    path: String,
+    http_version: &'static str,
+    status: u16,
+    bytes: u64,
+    duration: f64,
+    worker: Option<usize>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Entry {
+    pub fn new<B>(req: &Request<B>, client: Option<SocketAddr>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+
+        Self {
+            time: SystemTime::now(),
+            request_id: request_id(req.headers()),
+            client,
+            method: req.method().to_string(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.uri().to_string(), |path| path.to_string()),
+            http_version: match req.version() {
+                Version::HTTP_09 => "HTTP/0.9",
+                Version::HTTP_10 => "HTTP/1.0",
+                Version::HTTP_2 => "HTTP/2.0",
+                Version::HTTP_3 => "HTTP/3.0",
+                _ => "HTTP/1.1",
+            },
+            status: 0,
+            bytes: 0,
+            duration: 0.0,
+            worker: None,
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn finish(&mut self, status: u16, bytes: u64, worker: Option<usize>) {
+        self.status = status;
+        self.bytes = bytes;
+        self.worker = worker;
+        self.duration = self.started.elapsed().as_secs_f64();
+    }
+
+    fn combined(&self) -> String {
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "\"-\"".to_string(),
+        };
+
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} {} {} {:.6} {} {}",
+            self.client
+                .map_or_else(|| "-".to_string(), |client| client.ip().to_string()),
+            clf_time(self.time),
+            self.method,
+            self.path,
+            self.http_version,
+            self.status,
+            self.bytes,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            self.duration,
+            self.worker
+                .map_or_else(|| "-".to_string(), |worker| worker.to_string()),
+            self.request_id,
+        )
+    }
+
+    fn json(&self) -> String {
+        serde_json::to_string(self).expect("access log entries always serialize")
+    }
+}
+
+/// Keeps the id a client or proxy sent, or makes one up that is unique to this process.
+fn request_id(headers: &HeaderMap) -> String {
+    static PREFIX: LazyLock<u32> = LazyLock::new(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos() ^ std::process::id())
+    });
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let sent = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN);
+
+    match sent {
+        Some(id) => id.to_string(),
+        None => format!(
+            "{:08x}{:08x}",
+            *PREFIX,
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ),
+    }
+}
+
+fn serialize_time<S: serde::Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let (year, month, day, hour, minute, second) = civil_time(*time);
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_millis());
+
+    serializer.serialize_str(&format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    ))
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Splits a time into UTC year, month, day, hour, minute and second, see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let (days, of_day) = (seconds.div_euclid(86400), seconds.rem_euclid(86400) as u32);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn formats_times_in_utc() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_164_800);
+        assert_eq!(clf_time(leap_day), "29/Feb/2024:00:00:00 +0000");
+    }
+
+    #[test]
+    fn writes_combined_lines() {
+        let req = Request::get("/items?page=2")
+            .header(USER_AGENT, "curl/8.0 \"test\"")
+            .header(REQUEST_ID_HEADER, "abc")
+            .body(())
+            .unwrap();
+        let mut entry = Entry::new(&req, Some("10.0.0.1:5000".parse().unwrap()));
+        entry.time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        entry.finish(200, 42, Some(3));
+        entry.duration = 0.5;
+
+        assert_eq!(
+            entry.combined(),
+            "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /items?page=2 HTTP/1.1\" 200 42 \
+             \"-\" \"curl/8.0 \\\"test\\\"\" 0.500000 3 abc"
+        );
+    }
+}
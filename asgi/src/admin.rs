@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, error};
+
+use crate::listener::Listeners;
+use crate::supervisor::Supervisor;
+
+/// Serves the admin API on `listeners` until the server exits:
+///
+/// - `GET /workers` lists the workers
+/// - `POST /workers/scale?count=N` starts or drains workers until N are left
+/// - `POST /workers/add` and `POST /workers/remove` start one worker or drain the newest
+/// - `POST /workers/{id}/drain` stops a worker once it answered its requests
+/// - `POST /reload` replaces every worker with a new one
+pub async fn serve(mut listeners: Listeners, supervisor: Arc<Supervisor>) {
+    loop {
+        let stream = match listeners.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!(error = %err, "Failed to accept admin connection");
+                continue;
+            }
+        };
+
+        let supervisor = Arc::clone(&supervisor);
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let supervisor = Arc::clone(&supervisor);
+
+                async move {
+                    let response = match handle(req, &supervisor).await {
+                        Ok(response) => response,
+                        Err((status, message)) => {
+                            json_response(status, &json!({ "error": message }))
+                        }
+                    };
+
+                    Ok::<_, hyper::Error>(response)
+                }
+            });
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(error = %err, "Error serving admin connection");
+            }
+        });
+    }
+}
+
+async fn handle<B>(
+    req: Request<B>,
+    supervisor: &Arc<Supervisor>,
+) -> Result<Response<Full<Bytes>>, (StatusCode, String)> {
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["workers"]) => Ok(json_response(StatusCode::OK, &supervisor.list())),
+        (&Method::POST, ["workers", "scale"]) => {
+            let count = query_param(&req, "count")
+                .and_then(|count| count.parse().ok())
+                .ok_or((
+                    StatusCode::BAD_REQUEST,
+                    "Expected a worker count, e.g. ?count=4".to_string(),
+                ))?;
+
+            supervisor
+                .scale(count)
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+            Ok(json_response(StatusCode::ACCEPTED, &supervisor.list()))
+        }
+        (&Method::POST, ["workers", "add"]) => {
+            supervisor
+                .add()
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+            Ok(json_response(StatusCode::ACCEPTED, &supervisor.list()))
+        }
+        (&Method::POST, ["workers", "remove"]) => {
+            supervisor
+                .remove()
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+            Ok(json_response(StatusCode::ACCEPTED, &supervisor.list()))
+        }
+        (&Method::POST, ["workers", id, "drain"]) => {
+            let id = id
+                .parse()
+                .map_err(|_| (StatusCode::NOT_FOUND, format!("No worker {}", id)))?;
+
+            supervisor
+                .drain(id)
+                .await
+                .map_err(|err| (StatusCode::NOT_FOUND, err))?;
+            Ok(json_response(StatusCode::ACCEPTED, &supervisor.list()))
+        }
+        (&Method::POST, ["reload"]) => {
+            supervisor
+                .reload()
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+            Ok(json_response(StatusCode::ACCEPTED, &supervisor.list()))
+        }
+        (
+            _,
+            ["workers"]
+            | ["workers", "scale" | "add" | "remove"]
+            | ["workers", _, "drain"]
+            | ["reload"],
+        ) => Err((
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("{} not allowed", req.method()),
+        )),
+        _ => Err((StatusCode::NOT_FOUND, "Not found".to_string())),
+    }
+}
+
+fn query_param<'a, B>(req: &'a Request<B>, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn json_response<T: Serialize + ?Sized>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(body).expect("admin responses always serialize");
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::Arguments;
+    use crate::metrics::Metrics;
+
+    async fn status(supervisor: &Arc<Supervisor>, method: Method, uri: &str) -> StatusCode {
+        let req = Request::builder().method(method).uri(uri).body(()).unwrap();
+
+        match handle(req, supervisor).await {
+            Ok(response) => response.status(),
+            Err((status, _)) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_requests_and_rejects_bad_ones() {
+        let cli = Arc::new(Arguments::parse_from(["asgi"]));
+        let supervisor = Supervisor::new(cli, Arc::new(Metrics::new()), 0..=0);
+
+        assert_eq!(
+            status(&supervisor, Method::GET, "/workers").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&supervisor, Method::POST, "/workers/scale?count=0").await,
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(
+            status(&supervisor, Method::POST, "/workers/scale").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(&supervisor, Method::POST, "/workers/scale?count=1").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(&supervisor, Method::POST, "/workers/add").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(&supervisor, Method::POST, "/workers/7/drain").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(&supervisor, Method::POST, "/workers/x/drain").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(&supervisor, Method::GET, "/reload").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(&supervisor, Method::DELETE, "/workers").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(&supervisor, Method::GET, "/").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+
+use clap::Parser;
+use messages::codec::DEFAULT_MAX_FRAME_SIZE;
+use observability::logging::LogFormat;
+use tracing::level_filters::LevelFilter;
+
+use crate::access_log::Format;
+use crate::balancer::Strategy;
+use crate::compression::Encoding;
+use crate::proxy::AllowedIps;
+use crate::static_files::Mount;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Arguments {
+    #[arg(value_name = "MODULE:APP", default_value = "echo_server:app")]
+    pub module: String,
+    #[arg(value_name = "WORKERS", default_value_t = 1)]
+    pub workers: usize,
+    /// TCP address to listen on, can be given multiple times
+    #[arg(short, long, value_name = "HOST:PORT")]
+    pub bind: Vec<SocketAddr>,
+    /// Unix domain socket to listen on, can be given multiple times
+    #[arg(long, value_name = "PATH")]
+    pub uds: Vec<PathBuf>,
+    /// Permissions applied to the --uds sockets, in octal
+    #[arg(long, value_name = "MODE", value_parser = parse_octal_mode)]
+    pub uds_mode: Option<u32>,
+    /// Owner applied to the --uds sockets
+    #[arg(long, value_name = "UID")]
+    pub uds_owner: Option<u32>,
+    /// Group applied to the --uds sockets
+    #[arg(long, value_name = "GID")]
+    pub uds_group: Option<u32>,
+    /// Already bound listening socket inherited from the parent process
+    #[arg(long, value_name = "FD")]
+    pub fd: Vec<RawFd>,
+    /// Prefix the application is mounted under, passed to it as the ASGI root_path
+    #[arg(long, value_name = "PATH", default_value = "")]
+    pub root_path: String,
+    /// Proxy addresses or networks trusted to set X-Forwarded-* headers, "*" trusts everyone
+    #[arg(
+        long,
+        value_name = "IPS",
+        value_delimiter = ',',
+        default_value = "127.0.0.1"
+    )]
+    pub forwarded_allow_ips: Vec<AllowedIps>,
+    /// Largest message exchanged with the workers, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    pub max_frame_size: usize,
+    /// Idle connections kept open to each worker for reuse
+    #[arg(long, value_name = "COUNT", default_value_t = 16)]
+    pub worker_connections: usize,
+    /// How requests are spread across the workers
+    #[arg(long, value_enum, default_value_t = Strategy::RoundRobin)]
+    pub balancer: Strategy,
+    /// Requests handled at once before new ones are answered with 503
+    #[arg(long, value_name = "COUNT")]
+    pub limit_concurrency: Option<usize>,
+    /// Most workers started when the load is high, enables autoscaling
+    #[arg(long, value_name = "COUNT")]
+    pub autoscale_max: Option<usize>,
+    /// Fewest workers kept running when autoscaling
+    #[arg(long, value_name = "COUNT", default_value_t = 1)]
+    pub autoscale_min: usize,
+    /// Requests in flight to a single worker before it is skipped by the balancer
+    #[arg(long, value_name = "COUNT")]
+    pub limit_worker_concurrency: Option<usize>,
+    /// Requests each worker queues for the app before answering with 503
+    #[arg(long, value_name = "COUNT", default_value_t = 64)]
+    pub worker_queue_size: usize,
+    /// Largest request body accepted, in bytes, bigger ones are answered with 413
+    #[arg(long, value_name = "BYTES", default_value_t = 16 * 1024 * 1024)]
+    pub limit_request_body: usize,
+    /// Headers allowed in a request, more are answered with 431 [default: 100]
+    #[arg(long, value_name = "COUNT")]
+    pub limit_request_headers: Option<usize>,
+    /// Space for the request line and headers, in bytes, beyond it requests are answered
+    /// with 431 [default: about 400KiB]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(8192..))]
+    pub limit_request_header_size: Option<u64>,
+    /// Seconds a client gets to send a request's headers
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub timeout_header_read: u64,
+    /// Seconds an idle connection is kept open waiting for the next request
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    pub timeout_keep_alive: u64,
+    /// Requests served on a connection before it is closed
+    #[arg(long, value_name = "COUNT")]
+    pub keep_alive_requests: Option<usize>,
+    /// File the access log is written to, "-" for stdout
+    #[arg(long, value_name = "PATH", default_value = "-")]
+    pub access_log: String,
+    /// Layout of the access log lines
+    #[arg(long, value_enum, default_value_t = Format::Combined)]
+    pub access_log_format: Format,
+    /// Don't write an access log
+    #[arg(long)]
+    pub no_access_log: bool,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[arg(long, value_name = "HOST:PORT")]
+    pub metrics_bind: Option<SocketAddr>,
+    /// Directory served as is under a URL prefix, e.g. /assets=./public, can be given
+    /// multiple times
+    #[arg(long = "static", value_name = "PREFIX=DIR", value_parser = Mount::parse)]
+    pub static_mounts: Vec<Mount>,
+    /// Encodings worker responses are compressed with, in order of preference, e.g.
+    /// zstd,br,gzip; none by default
+    #[arg(long, value_name = "ENCODINGS", value_enum, value_delimiter = ',')]
+    pub compression: Vec<Encoding>,
+    /// Smallest response body compressed, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+    pub compression_min_size: u64,
+    /// Content types compressed, type/* matching a whole family
+    #[arg(
+        long,
+        value_name = "TYPES",
+        value_delimiter = ',',
+        default_value = "text/*,application/json,application/javascript,application/xml,image/svg+xml"
+    )]
+    pub compression_types: Vec<String>,
+    /// Path answered with 200 while the server is up, e.g. /__ferricorn/health
+    #[arg(long, value_name = "PATH")]
+    pub health_path: Option<String>,
+    /// Path answered with 200 once enough workers are up, 503 until then, e.g.
+    /// /__ferricorn/ready
+    #[arg(long, value_name = "PATH")]
+    pub ready_path: Option<String>,
+    /// Workers that must be up, with the app started, for the server to be ready
+    #[arg(long, value_name = "COUNT", default_value_t = 1)]
+    pub ready_min_workers: usize,
+    /// Loopback address to serve the admin API on, it has no authentication
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_loopback)]
+    pub admin_bind: Option<SocketAddr>,
+    /// Unix domain socket to serve the admin API on, only accessible to the server's user
+    #[arg(long, value_name = "PATH")]
+    pub admin_uds: Option<PathBuf>,
+    /// Least severe messages logged, from trace to error, or off
+    #[arg(long, value_name = "LEVEL", default_value_t = LevelFilter::INFO)]
+    pub log_level: LevelFilter,
+    /// Layout of the log lines, pretty or json, workers use the same
+    #[arg(long, value_name = "FORMAT", default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+    /// OTLP/HTTP traces URL to export request spans to, e.g. http://localhost:4318/v1/traces
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+    /// Pending connections the kernel queues on the listening sockets
+    #[arg(long, value_name = "COUNT", default_value_t = 1024)]
+    pub backlog: i32,
+}
+
+fn parse_octal_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim_start_matches("0o");
+
+    u32::from_str_radix(digits, 8).map_err(|_| format!("Invalid octal mode: {}", value))
+}
+
+fn parse_loopback(value: &str) -> Result<SocketAddr, String> {
+    let addr = value
+        .parse::<SocketAddr>()
+        .map_err(|err| format!("{}: {}", err, value))?;
+
+    match addr.ip().to_canonical().is_loopback() {
+        true => Ok(addr),
+        false => Err(format!(
+            "{} isn't a loopback address, the admin API has no authentication",
+            value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_octal_modes() {
+        assert_eq!(parse_octal_mode("660"), Ok(0o660));
+        assert_eq!(parse_octal_mode("0o600"), Ok(0o600));
+        assert_eq!(parse_octal_mode("0755"), Ok(0o755));
+        assert!(parse_octal_mode("8").is_err());
+        assert!(parse_octal_mode("rw").is_err());
+        assert!(parse_octal_mode("").is_err());
+    }
+
+    #[test]
+    fn only_binds_the_admin_api_to_loopback() {
+        assert!(parse_loopback("127.0.0.1:9101").is_ok());
+        assert!(parse_loopback("[::1]:9101").is_ok());
+        assert!(parse_loopback("[::ffff:127.0.0.1]:9101").is_ok());
+        assert!(parse_loopback("0.0.0.0:9101").is_err());
+        assert!(parse_loopback("192.0.2.1:9101").is_err());
+        assert!(parse_loopback("[::]:9101").is_err());
+        assert!(parse_loopback("localhost").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::supervisor::{State, Supervisor};
+
+/// How often the load is looked at.
+const INTERVAL: Duration = Duration::from_secs(5);
+/// A worker runs one request at a time, on average each is busy with anything waiting behind.
+const SCALE_UP_LOAD: f64 = 1.0;
+const SCALE_DOWN_LOAD: f64 = 0.25;
+/// Checks in a row the load has to stay low before a worker is drained, so that a short
+/// lull doesn't undo a scale up.
+const SCALE_DOWN_CHECKS: u32 = 6;
+
+/// Adds a worker when they are all busy and drains one after they have been mostly idle for
+/// a while, one at a time and only once new workers are up.
+pub struct Autoscaler {
+    bounds: RangeInclusive<usize>,
+    quiet_checks: u32,
+}
+
+impl Autoscaler {
+    pub fn new(bounds: RangeInclusive<usize>) -> Self {
+        Self {
+            bounds,
+            quiet_checks: 0,
+        }
+    }
+
+    /// The worker count to aim for, given the load of each running worker: the requests it
+    /// has in flight or queued, whichever is higher.
+    pub fn next_count(&mut self, loads: &[u64]) -> usize {
+        let count = loads.len();
+        if count == 0 {
+            return (*self.bounds.start()).max(1);
+        }
+
+        let load = loads.iter().sum::<u64>() as f64 / count as f64;
+
+        if load < SCALE_DOWN_LOAD {
+            self.quiet_checks += 1;
+        } else {
+            self.quiet_checks = 0;
+        }
+
+        let next = if load >= SCALE_UP_LOAD {
+            count + 1
+        } else if self.quiet_checks >= SCALE_DOWN_CHECKS {
+            self.quiet_checks = 0;
+            count - 1
+        } else {
+            count
+        };
+
+        next.clamp(*self.bounds.start(), *self.bounds.end())
+    }
+}
+
+/// Resizes the pool with the load until the server exits.
+pub async fn run(supervisor: Arc<Supervisor>, mut autoscaler: Autoscaler) {
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Queue depths are as of the workers' last heartbeats
+        let workers = supervisor.list();
+        if workers.iter().any(|worker| worker.state == State::Starting) {
+            continue;
+        }
+
+        let loads: Vec<u64> = workers
+            .iter()
+            .filter(|worker| worker.state == State::Running)
+            .map(|worker| (worker.in_flight as u64).max(worker.queue_depth.unwrap_or(0)))
+            .collect();
+        let current = supervisor.count();
+        let next = autoscaler.next_count(&loads);
+
+        if next != current {
+            info!(from = current, to = next, "Autoscaling workers");
+
+            if let Err(err) = supervisor.scale(next).await {
+                warn!(error = %err, "Failed to autoscale workers");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_up_when_busy_and_down_after_a_lull() {
+        let mut autoscaler = Autoscaler::new(1..=3);
+
+        assert_eq!(autoscaler.next_count(&[1, 2]), 3);
+        assert_eq!(autoscaler.next_count(&[2, 2, 2]), 3);
+
+        for _ in 1..SCALE_DOWN_CHECKS {
+            assert_eq!(autoscaler.next_count(&[0, 0, 0]), 3);
+        }
+        assert_eq!(autoscaler.next_count(&[0, 0, 0]), 2);
+        assert_eq!(autoscaler.next_count(&[0, 0]), 2);
+
+        // Any busy check starts the lull over
+        assert_eq!(autoscaler.next_count(&[1, 1]), 3);
+        assert_eq!(Autoscaler::new(2..=4).next_count(&[0]), 2);
+    }
+}
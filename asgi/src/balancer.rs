@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use crate::pool::{InFlight, WorkerPool};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Each worker in turn, regardless of load
+    RoundRobin,
+    /// The worker with the fewest requests in flight
+    LeastOutstanding,
+    /// The less busy of two workers picked at random
+    PowerOfTwo,
+}
+
+/// Picks the worker each request is sent to.
+pub struct Balancer {
+    strategy: Strategy,
+    max_in_flight: Option<usize>,
+    next: AtomicUsize,
+    rng: AtomicU64,
+}
+
+impl Balancer {
+    /// Workers with `max_in_flight` requests outstanding are skipped.
+    pub fn new(strategy: Strategy, max_in_flight: Option<usize>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+
+        Self {
+            strategy,
+            max_in_flight,
+            next: AtomicUsize::new(0),
+            // xorshift gets stuck on zero
+            rng: AtomicU64::new(seed | 1),
+        }
+    }
+
+    /// Returns `None` when there are no workers or all of them are at their limit, otherwise
+    /// the worker along with the request's slot on it.
+    pub fn pick(&self, workers: &[Arc<WorkerPool>]) -> Option<(Arc<WorkerPool>, InFlight)> {
+        if workers.is_empty() {
+            return None;
+        }
+
+        let index = match self.strategy {
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % workers.len(),
+            Strategy::LeastOutstanding => {
+                // Ties are common when idle, rotate where the scan starts so they're spread out
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+                (0..workers.len())
+                    .map(|i| (start + i) % workers.len())
+                    .min_by_key(|&i| workers[i].in_flight())
+                    .unwrap()
+            }
+            Strategy::PowerOfTwo => {
+                let first = self.random() % workers.len();
+                let second = self.random() % workers.len();
+
+                if workers[second].in_flight() < workers[first].in_flight() {
+                    second
+                } else {
+                    first
+                }
+            }
+        };
+
+        if let Some(in_flight) = workers[index].try_start_request(self.max_in_flight) {
+            return Some((Arc::clone(&workers[index]), in_flight));
+        }
+
+        // Whichever worker has room, the strategy's choice can't take the request
+        let mut others: Vec<_> = workers.iter().collect();
+        others.sort_by_key(|worker| worker.in_flight());
+
+        others.into_iter().find_map(|worker| {
+            let in_flight = worker.try_start_request(self.max_in_flight)?;
+            Some((Arc::clone(worker), in_flight))
+        })
+    }
+
+    fn random(&self) -> usize {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+
+        x as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workers(count: usize) -> Vec<Arc<WorkerPool>> {
+        (0..count)
+            .map(|i| Arc::new(WorkerPool::new(i, format!("/tmp/worker_{}", i), 1024, 1)))
+            .collect()
+    }
+
+    #[test]
+    fn least_outstanding_avoids_busy_workers() {
+        let workers = workers(3);
+        let balancer = Balancer::new(Strategy::LeastOutstanding, None);
+        let _busy = [workers[0].start_request(), workers[2].start_request()];
+
+        for _ in 0..5 {
+            let (picked, _) = balancer.pick(&workers).unwrap();
+            assert_eq!(picked.sock_file(), "/tmp/worker_1");
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let workers = workers(2);
+        let balancer = Balancer::new(Strategy::RoundRobin, None);
+        let _busy = workers[0].start_request();
+
+        let picked: Vec<_> = (0..4)
+            .map(|_| balancer.pick(&workers).unwrap().0.sock_file().to_string())
+            .collect();
+        assert_eq!(
+            picked,
+            [
+                "/tmp/worker_0",
+                "/tmp/worker_1",
+                "/tmp/worker_0",
+                "/tmp/worker_1"
+            ]
+        );
+        assert!(balancer.pick(&[]).is_none());
+    }
+
+    #[test]
+    fn skips_workers_at_their_limit() {
+        let workers = workers(2);
+        let balancer = Balancer::new(Strategy::RoundRobin, Some(1));
+        let _busy = workers[0].start_request();
+
+        for _ in 0..3 {
+            let (picked, _) = balancer.pick(&workers).unwrap();
+            assert_eq!(picked.sock_file(), "/tmp/worker_1");
+        }
+
+        let _also_busy = workers[1].start_request();
+        assert!(balancer.pick(&workers).is_none());
+    }
+
+    #[test]
+    fn reserves_the_slot_it_picked() {
+        let workers = workers(2);
+        let balancer = Balancer::new(Strategy::LeastOutstanding, Some(1));
+
+        // Picked before either request reached the worker
+        let (first, _first) = balancer.pick(&workers).unwrap();
+        let (second, _second) = balancer.pick(&workers).unwrap();
+
+        assert_ne!(first.id(), second.id());
+        assert!(balancer.pick(&workers).is_none());
+        drop(_first);
+        assert_eq!(balancer.pick(&workers).unwrap().0.id(), first.id());
+    }
+}
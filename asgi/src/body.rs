@@ -0,0 +1,316 @@
+use std::convert::Infallible;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::stream::{iter, poll_fn};
+use futures_util::StreamExt;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use messages::types::{ASGIMessages, HttpResponseFile};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+use tracing::error;
+
+use crate::pool::{InFlight, WorkerPool};
+use crate::upstream::WorkerConnection;
+
+/// Body messages from a worker buffered ahead of a slow client.
+const STREAM_BUFFER: usize = 16;
+/// Size of the reads when sending a file, each one a body frame.
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Body of every response sent to clients, whether it's complete or still coming from a
+/// worker. An error aborts the connection, the client can't take the response as complete.
+pub type ResponseBody = BoxBody<Bytes, io::Error>;
+
+pub fn full(body: impl Into<Bytes>) -> ResponseBody {
+    boxed(Full::new(body.into()))
+}
+
+pub fn boxed(body: Full<Bytes>) -> ResponseBody {
+    body.map_err(|never: Infallible| match never {}).boxed()
+}
+
+/// A body that fails right away, aborting the response.
+pub fn failed(err: io::Error) -> ResponseBody {
+    BodyExt::boxed(StreamBody::new(iter([Err(err)])))
+}
+
+/// Counts the bytes of `body` as they go out, `finished` gets the count once the body ended,
+/// failed or was dropped unfinished, e.g. because the client went away.
+pub fn observed(
+    body: ResponseBody,
+    finished: impl FnOnce(u64) + Send + Sync + 'static,
+) -> ResponseBody {
+    BodyExt::boxed(Observed {
+        inner: body,
+        bytes: 0,
+        finished: Some(Box::new(finished)),
+    })
+}
+
+type Finished = Box<dyn FnOnce(u64) + Send + Sync>;
+
+struct Observed {
+    inner: ResponseBody,
+    bytes: u64,
+    finished: Option<Finished>,
+}
+
+impl Observed {
+    fn finish(&mut self) {
+        if let Some(finished) = self.finished.take() {
+            finished(self.bytes);
+        }
+    }
+}
+
+impl Body for Observed {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.get_mut();
+        let frame = std::task::ready!(Pin::new(&mut this.inner).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.bytes += data.len() as u64;
+                }
+            }
+            Some(Err(_)) | None => this.finish(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Observed {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Streams the rest of a response body from `connection`, `first` being the first body
+/// message the worker sent, with its file in `first_file` when it points at one, and then
+/// its `trailers` when the response announced them. The connection goes back to `pool` once
+/// the last part arrived, and the request counts as in flight until then.
+pub fn from_worker(
+    mut connection: WorkerConnection,
+    pool: Arc<WorkerPool>,
+    first: ASGIMessages,
+    mut first_file: Option<OpenedFile>,
+    trailers: bool,
+    in_flight: InFlight,
+) -> ResponseBody {
+    let (tx, mut rx) = mpsc::channel::<io::Result<Frame<Bytes>>>(STREAM_BUFFER);
+
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        let worker = pool.id();
+        let mut next = Some(first);
+        let mut trailer_fields = HeaderMap::new();
+
+        loop {
+            let message = match next.take() {
+                Some(message) => Some(Ok(message)),
+                None => connection.framed.next().await,
+            };
+
+            let failure = match message {
+                Some(Ok(ASGIMessages::HttpResponseBody(body))) => {
+                    let more_body = body.more_body;
+
+                    if !body.body.is_empty()
+                        && tx
+                            .send(Ok(Frame::data(Bytes::from(body.body))))
+                            .await
+                            .is_err()
+                    {
+                        // The client is gone, the rest of the response would be out of sync
+                        return;
+                    }
+
+                    if !more_body && !trailers {
+                        pool.put(connection);
+                        return;
+                    }
+                    continue;
+                }
+                Some(Ok(ASGIMessages::HttpResponseFile(file))) => {
+                    let opened = match first_file.take() {
+                        Some(opened) => Ok(opened),
+                        None => OpenedFile::open(&file).await,
+                    };
+                    let sent = match opened {
+                        Ok(opened) => send_file(&tx, opened).await,
+                        Err(err) => Err(err),
+                    };
+
+                    match sent {
+                        Ok(true) if !file.more_body && !trailers => {
+                            pool.put(connection);
+                            return;
+                        }
+                        Ok(true) => continue,
+                        Ok(false) => return,
+                        Err(err) => format!("Failed to send {}: {}", file.path.display(), err),
+                    }
+                }
+                Some(Ok(ASGIMessages::HttpResponseTrailers(message))) if trailers => {
+                    match add_trailers(&mut trailer_fields, message.headers) {
+                        Ok(()) if message.more_trailers => continue,
+                        Ok(()) => {
+                            let fields = std::mem::take(&mut trailer_fields);
+                            if !fields.is_empty()
+                                && tx.send(Ok(Frame::trailers(fields))).await.is_err()
+                            {
+                                return;
+                            }
+                            pool.put(connection);
+                            return;
+                        }
+                        Err(failure) => failure,
+                    }
+                }
+                Some(Ok(_)) => {
+                    "Worker sent an unexpected message in the middle of a response".to_string()
+                }
+                Some(Err(err)) => format!("Invalid response from worker: {}", err),
+                None => "Worker closed the connection mid-response".to_string(),
+            };
+
+            error!(worker, "{}", failure);
+            let _ = tx.send(Err(io::Error::other(failure))).await;
+            return;
+        }
+    });
+
+    BodyExt::boxed(StreamBody::new(poll_fn(move |cx| rx.poll_recv(cx))))
+}
+
+fn add_trailers(fields: &mut HeaderMap, headers: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), String> {
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(&name);
+        let value = HeaderValue::from_bytes(&value);
+
+        match (name, value) {
+            (Ok(name), Ok(value)) => fields.append(name, value),
+            _ => return Err("Worker sent an invalid trailer".to_string()),
+        };
+    }
+
+    Ok(())
+}
+
+/// The part of a file a worker pointed at, open and positioned at its start.
+pub struct OpenedFile {
+    file: Take<File>,
+    length: u64,
+}
+
+impl OpenedFile {
+    pub async fn open(file: &HttpResponseFile) -> io::Result<Self> {
+        let mut opened = File::open(&file.path).await?;
+        let size = opened.metadata().await?.len();
+        let rest = size.saturating_sub(file.offset);
+        let length = file.count.map_or(rest, |count| count.min(rest));
+
+        if file.offset > 0 {
+            opened.seek(SeekFrom::Start(file.offset)).await?;
+        }
+
+        Ok(Self {
+            file: opened.take(length),
+            length,
+        })
+    }
+
+    /// Bytes that will be sent.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+/// Reads the part of the file the worker pointed at into `tx`, returning false when the
+/// client is gone.
+async fn send_file(
+    tx: &mpsc::Sender<io::Result<Frame<Bytes>>>,
+    opened: OpenedFile,
+) -> io::Result<bool> {
+    let mut chunks = ReaderStream::with_capacity(opened.file, FILE_CHUNK_SIZE);
+    while let Some(chunk) = chunks.next().await {
+        if tx.send(Ok(Frame::data(chunk?))).await.is_err() {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_bytes_once_the_body_is_done() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let body = observed(full("hello"), move |bytes| tx.send(bytes).unwrap());
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        assert_eq!(rx.try_recv(), Ok(5));
+        assert!(rx.try_recv().is_err());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        drop(observed(full("unsent"), move |bytes| {
+            tx.send(bytes).unwrap()
+        }));
+        assert_eq!(rx.try_recv(), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn sends_the_requested_part_of_a_file() {
+        let path = std::env::temp_dir().join(format!("ferricorn_body_{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = HttpResponseFile::new(path.clone(), 2, Some(5), false);
+        let opened = OpenedFile::open(&file).await.unwrap();
+        let length = opened.length();
+
+        let (tx, mut rx) = mpsc::channel(STREAM_BUFFER);
+        assert!(send_file(&tx, opened).await.unwrap());
+        drop(tx);
+
+        let mut sent = Vec::new();
+        while let Some(frame) = rx.recv().await {
+            sent.extend_from_slice(&frame.unwrap().into_data().unwrap());
+        }
+        let past_the_end = OpenedFile::open(&HttpResponseFile::new(path.clone(), 8, None, false))
+            .await
+            .unwrap()
+            .length();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(sent, b"23456");
+        assert_eq!(length, 5);
+        assert_eq!(past_the_end, 2);
+    }
+}
@@ -0,0 +1,424 @@
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame};
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+
+use crate::body::{self, ResponseBody};
+
+// Levels that keep compressing on the fly cheap, the highest ones are meant for assets
+// compressed ahead of time
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER: usize = 4096;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Br => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compresses worker responses with whichever of the enabled encodings the client prefers.
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    content_types: Vec<String>,
+}
+
+impl Compression {
+    /// `encodings` in order of preference, none disables compression. `content_types` are
+    /// MIME types, `type/*` standing for a whole family.
+    pub fn new(encodings: Vec<Encoding>, min_size: u64, content_types: Vec<String>) -> Self {
+        Self {
+            encodings,
+            min_size,
+            content_types: content_types
+                .into_iter()
+                .map(|content_type| content_type.trim().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// The encoding the response to `req` can be compressed with, the client's weights
+    /// deciding and our order breaking ties.
+    pub fn negotiate<B>(&self, req: &Request<B>) -> Option<Encoding> {
+        if req.method() == Method::HEAD {
+            return None;
+        }
+
+        let accepted = req.headers().get(ACCEPT_ENCODING)?.to_str().ok()?;
+        let mut best: Option<(Encoding, f32)> = None;
+
+        for &encoding in &self.encodings {
+            let weight = weight(accepted, encoding.as_str());
+
+            if weight > 0.0 && best.is_none_or(|(_, best)| weight > best) {
+                best = Some((encoding, weight));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Compresses `response` unless it's already encoded, too small or not a content type
+    /// worth compressing. Complete bodies are compressed at once, streamed ones part by part
+    /// as they come so that the stream doesn't stall.
+    pub async fn apply(
+        &self,
+        encoding: Encoding,
+        response: Response<ResponseBody>,
+    ) -> Response<ResponseBody> {
+        if !self.applies(&response) {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let headers = &mut parts.headers;
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        vary_on_accept_encoding(headers);
+        // The compressed bytes differ, a strong validator would claim they are the same
+        if let Some(etag) = headers.get(ETAG).and_then(|etag| etag.to_str().ok()) {
+            if etag.starts_with('"') {
+                let weak = HeaderValue::from_str(&format!("W/{}", etag)).unwrap();
+                headers.insert(ETAG, weak);
+            }
+        }
+
+        let body = match body.size_hint().exact() {
+            Some(_) => match compress_all(encoding, body).await {
+                Ok(compressed) => {
+                    let length = compressed.len() as u64;
+                    parts
+                        .headers
+                        .insert(CONTENT_LENGTH, HeaderValue::from(length));
+                    body::full(compressed)
+                }
+                Err(err) => body::failed(err),
+            },
+            None => match Encoder::new(encoding) {
+                Ok(encoder) => BodyExt::boxed(Compressed {
+                    inner: body,
+                    encoder: Some(encoder),
+                    trailers: None,
+                }),
+                Err(err) => body::failed(err),
+            },
+        };
+
+        Response::from_parts(parts, body)
+    }
+
+    fn applies(&self, response: &Response<ResponseBody>) -> bool {
+        let status = response.status();
+        let headers = response.headers();
+
+        if self.encodings.is_empty()
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || headers.contains_key(CONTENT_ENCODING)
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return false;
+        }
+
+        let no_transform = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        if no_transform {
+            return false;
+        }
+
+        let declared_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+        let length = response.body().size_hint().exact().or(declared_length);
+        if length.is_some_and(|length| length < self.min_size) {
+            return false;
+        }
+
+        let Some(content_type) = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(family) => mime
+                    .split_once('/')
+                    .is_some_and(|(mime_family, _)| mime_family == family),
+                None => *allowed == mime,
+            })
+    }
+}
+
+/// The weight an Accept-Encoding value gives `encoding`, `*` covering anything not named.
+fn weight(accepted: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+
+    for item in accepted.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let weight = params
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(1.0, |q| q.parse::<f32>().unwrap_or(0.0));
+
+        if name.eq_ignore_ascii_case(encoding) {
+            return weight;
+        }
+        if name == "*" {
+            wildcard = weight;
+        }
+    }
+
+    wildcard
+}
+
+fn vary_on_accept_encoding(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+async fn compress_all(encoding: Encoding, body: ResponseBody) -> io::Result<Bytes> {
+    let body = body.collect().await?.to_bytes();
+
+    // Up to the max frame size, brotli especially would hold up every other connection
+    // served by this thread
+    tokio::task::spawn_blocking(move || {
+        let mut encoder = Encoder::new(encoding)?;
+        let mut compressed = encoder.write(&body, false)?.to_vec();
+        compressed.extend_from_slice(&encoder.finish()?);
+
+        Ok(Bytes::from(compressed))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Br(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(GZIP_LEVEL),
+            )),
+            Encoding::Br => Encoder::Br(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?)
+            }
+        })
+    }
+
+    /// Compresses `data`, returning what's ready of the output. With `flush`, everything
+    /// written so far can be decoded from it.
+    fn write(&mut self, data: &[u8], flush: bool) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                if flush {
+                    encoder.flush()?;
+                }
+                encoder.get_mut()
+            }
+            Encoder::Br(encoder) => {
+                encoder.write_all(data)?;
+                if flush {
+                    encoder.flush()?;
+                }
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                if flush {
+                    encoder.flush()?;
+                }
+                encoder.get_mut()
+            }
+        };
+
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Br(encoder) => encoder.into_inner(),
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+
+        Ok(Bytes::from(output))
+    }
+}
+
+/// A streamed body compressed part by part, each flushed so the client gets it right away.
+struct Compressed {
+    inner: ResponseBody,
+    encoder: Option<Encoder>,
+    /// Trailers wait for the end of the compressed data
+    trailers: Option<Frame<Bytes>>,
+}
+
+impl Body for Compressed {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.get_mut();
+
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return match this.trailers.take() {
+                    Some(trailers) => Poll::Ready(Some(Ok(trailers))),
+                    None => Pin::new(&mut this.inner).poll_frame(cx),
+                };
+            };
+
+            let compressed = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => encoder.write(&data, true),
+                    Err(trailers) => {
+                        this.trailers = Some(trailers);
+                        this.encoder.take().unwrap().finish()
+                    }
+                },
+                Some(Err(err)) => Err(err),
+                None => this.encoder.take().unwrap().finish(),
+            };
+
+            match compressed {
+                Ok(data) if data.is_empty() => continue,
+                Ok(data) => return Poll::Ready(Some(Ok(Frame::data(data)))),
+                Err(err) => {
+                    this.encoder = None;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn compression() -> Compression {
+        Compression::new(
+            vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip],
+            16,
+            vec!["text/*".to_string(), "application/json".to_string()],
+        )
+    }
+
+    #[test]
+    fn negotiates_by_weight_then_preference() {
+        let negotiate = |accepted: &str| {
+            let req = Request::get("/")
+                .header(ACCEPT_ENCODING, accepted)
+                .body(())
+                .unwrap();
+            compression().negotiate(&req)
+        };
+
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Br));
+        assert_eq!(negotiate("gzip;q=1, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.1, gzip;q=0"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("identity"), None);
+    }
+
+    #[tokio::test]
+    async fn compresses_allowed_types_only() {
+        let text = "hello hello hello hello hello hello";
+        let response = |content_type: &str| {
+            Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .header(ETAG, "\"v1\"")
+                .body(body::full(text))
+                .unwrap()
+        };
+
+        let compressed = compression()
+            .apply(Encoding::Gzip, response("text/plain; charset=utf-8"))
+            .await;
+        assert_eq!(compressed.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(compressed.headers()[ETAG], "W/\"v1\"");
+        assert_eq!(compressed.headers()[VARY], "accept-encoding");
+
+        let bytes = compressed.into_body().collect().await.unwrap().to_bytes();
+        let mut decoded = String::new();
+        GzDecoder::new(&bytes[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let image = compression()
+            .apply(Encoding::Gzip, response("image/png"))
+            .await;
+        assert!(!image.headers().contains_key(CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,227 @@
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+
+use hyper::header::HeaderValue;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Sends `103 Early Hints` on a client connection ahead of the final response. hyper refuses
+/// to send informational responses, so they are written to the connection's stream while
+/// hyper has nothing of its own in flight, which is whenever it flushes.
+#[derive(Clone, Default)]
+pub struct EarlyHints(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    /// Whether the request being served may still get hints
+    open: bool,
+    pending: Vec<u8>,
+    /// Bytes of `pending` already on the wire, the rest must follow before anything else
+    written: usize,
+    /// Wakes the connection so that queued hints go out without waiting for hyper
+    waker: Option<Waker>,
+}
+
+impl EarlyHints {
+    pub fn wrap<S>(stream: S) -> (HintedStream<S>, EarlyHints) {
+        let hints = EarlyHints::default();
+        let stream = HintedStream {
+            inner: stream,
+            hints: hints.clone(),
+        };
+
+        (stream, hints)
+    }
+
+    /// Starts accepting hints for a new request, `allowed` being false for clients that
+    /// don't expect informational responses.
+    pub fn open(&self, allowed: bool) {
+        self.0.lock().unwrap().open = allowed;
+    }
+
+    /// Queues a 103 response with a Link header for each of `links`, unless the final
+    /// response is already on its way. Returns whether the hint will be sent.
+    pub fn send(&self, links: &[Vec<u8>]) -> bool {
+        let links: Vec<HeaderValue> = links
+            .iter()
+            .filter_map(|link| HeaderValue::from_bytes(link).ok())
+            .collect();
+
+        let mut state = self.0.lock().unwrap();
+        if !state.open || links.is_empty() {
+            return false;
+        }
+
+        state
+            .pending
+            .extend_from_slice(b"HTTP/1.1 103 Early Hints\r\n");
+        for link in links {
+            state.pending.extend_from_slice(b"link: ");
+            state.pending.extend_from_slice(link.as_bytes());
+            state.pending.extend_from_slice(b"\r\n");
+        }
+        state.pending.extend_from_slice(b"\r\n");
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    /// The final response is ready: hints that haven't started going out are dropped, they
+    /// would come after it otherwise.
+    pub fn close(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.open = false;
+
+        if state.written == 0 {
+            state.pending.clear();
+        }
+    }
+}
+
+/// A client connection's stream that [`EarlyHints`] can write to in between hyper's
+/// responses.
+pub struct HintedStream<S> {
+    inner: S,
+    hints: EarlyHints,
+}
+
+impl<S: AsyncWrite + Unpin> HintedStream<S> {
+    /// Writes the queued hints, only once hyper's buffered writes have all reached the
+    /// stream, or to finish a hint that started going out.
+    fn poll_hints(&mut self, cx: &mut Context<'_>, started_only: bool) -> Poll<io::Result<()>> {
+        let mut state = self.hints.0.lock().unwrap();
+        if started_only && state.written == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        while state.written < state.pending.len() {
+            let written = state.written;
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &state.pending[written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            state.written += n;
+        }
+
+        state.pending.clear();
+        state.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HintedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HintedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_hints(cx, true))?;
+
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_hints(cx, true))?;
+
+        Pin::new(&mut this.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.hints.0.lock().unwrap().waker = Some(cx.waker().clone());
+        ready!(this.poll_hints(cx, false))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_hints_between_responses_only() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut stream, hints) = EarlyHints::wrap(server);
+
+        assert!(!hints.send(&[b"</a.css>; rel=preload".to_vec()]));
+
+        hints.open(true);
+        assert!(hints.send(&[b"</a.css>; rel=preload".to_vec(), b"bad\r\nvalue".to_vec()]));
+        stream.flush().await.unwrap();
+
+        hints.send(&[b"</b.js>; rel=preload".to_vec()]);
+        hints.close();
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        drop(stream);
+
+        let mut written = String::new();
+        let mut client = client;
+        client.read_to_string(&mut written).await.unwrap();
+
+        assert_eq!(
+            written,
+            "HTTP/1.1 103 Early Hints\r\nlink: </a.css>; rel=preload\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn finishes_started_hints_before_vectored_writes() {
+        // Room for part of the hint only, the rest has to go out before the response
+        let (mut client, server) = tokio::io::duplex(16);
+        let (mut stream, hints) = EarlyHints::wrap(server);
+
+        hints.open(true);
+        hints.send(&[b"</a.css>; rel=preload".to_vec()]);
+        let reader = tokio::spawn(async move {
+            let mut written = String::new();
+            client.read_to_string(&mut written).await.unwrap();
+            written
+        });
+        let flushed = futures_util::poll!(std::pin::pin!(stream.flush()));
+        assert!(flushed.is_pending());
+
+        hints.close();
+        let response = b"HTTP/1.1 200 OK\r\n\r\n";
+        let mut written = 0;
+        while written < response.len() {
+            let parts = [IoSlice::new(&response[written..])];
+            written += stream.write_vectored(&parts).await.unwrap();
+        }
+        drop(stream);
+
+        assert_eq!(
+            reader.await.unwrap(),
+            "HTTP/1.1 103 Early Hints\r\nlink: </a.css>; rel=preload\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use hyper::Request;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Same as hyper, see http1::Builder::max_headers
+const DEFAULT_MAX_HEADERS: usize = 100;
+// More than hyper buffers for a head, it has rejected the request by then
+const MAX_LINE_SIZE: usize = 1024 * 1024;
+
+/// Header fields with lowercase names, in the order the client sent them.
+pub type HeaderFields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Keeps the header fields of the requests read on a client connection in the order they
+/// were sent. hyper's HeaderMap groups the values of a repeated header under its first
+/// occurrence, so the request heads are parsed again as they go by on their way to hyper.
+#[derive(Clone)]
+pub struct HeaderOrder(Arc<Mutex<Reader>>);
+
+struct Reader {
+    /// The start of a head or line cut off by the end of a read
+    buf: Vec<u8>,
+    framing: Framing,
+    max_headers: usize,
+    /// Whether the connection stops carrying requests after the current one
+    upgrade: bool,
+    heads: VecDeque<Head>,
+}
+
+struct Head {
+    method: String,
+    target: String,
+    fields: HeaderFields,
+}
+
+/// What is expected next on the connection.
+#[derive(Debug, PartialEq)]
+enum Framing {
+    Head,
+    Body(u64),
+    ChunkSize,
+    ChunkData(u64),
+    ChunkEnd,
+    Trailers,
+    /// Upgraded, or not understood, hyper's headers are used from then on
+    Stopped,
+}
+
+impl HeaderOrder {
+    pub fn wrap<S>(stream: S, max_headers: Option<usize>) -> (OrderedStream<S>, HeaderOrder) {
+        let order = HeaderOrder(Arc::new(Mutex::new(Reader {
+            buf: Vec::new(),
+            framing: Framing::Head,
+            max_headers: max_headers.unwrap_or(DEFAULT_MAX_HEADERS),
+            upgrade: false,
+            heads: VecDeque::new(),
+        })));
+        let stream = OrderedStream {
+            inner: stream,
+            order: order.clone(),
+        };
+
+        (stream, order)
+    }
+
+    /// The header fields of the next request on the connection, which must be called for
+    /// every request to stay in step. Falls back to hyper's order for a request whose head
+    /// couldn't be followed.
+    pub fn take<B>(&self, req: &Request<B>) -> HeaderFields {
+        let mut reader = self.0.lock().unwrap();
+
+        match reader.heads.pop_front() {
+            Some(head) if head.matches(req) => head.fields,
+            head => {
+                // Any later head would be paired with the wrong request
+                if head.is_some() {
+                    reader.stop();
+                    reader.heads.clear();
+                }
+
+                req.headers()
+                    .iter()
+                    .map(|(k, v)| (k.as_str().as_bytes().to_vec(), v.as_bytes().to_vec()))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Head {
+    fn matches<B>(&self, req: &Request<B>) -> bool {
+        self.method == req.method().as_str()
+            && *req.uri() == *self.target
+            && self.fields.len() == req.headers().len()
+    }
+}
+
+impl Reader {
+    fn feed(&mut self, mut data: &[u8]) {
+        if self.framing == Framing::Stopped {
+            return;
+        }
+
+        // What was cut off is completed a line at a time, heads and lines all end with one,
+        // so only they get copied and body bytes are counted off `data`
+        while !self.buf.is_empty() && !data.is_empty() {
+            let end = data
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(data.len(), |i| i + 1);
+            self.buf.extend_from_slice(&data[..end]);
+            data = &data[end..];
+
+            let mut buf = mem::take(&mut self.buf);
+            let n = self.consume(&buf);
+            if self.framing == Framing::Stopped {
+                return;
+            }
+            buf.drain(..n);
+            self.buf = buf;
+        }
+
+        if self.buf.is_empty() {
+            let n = self.consume(data);
+            if self.framing == Framing::Stopped {
+                return;
+            }
+            self.buf.extend_from_slice(&data[n..]);
+        }
+
+        if self.buf.len() > MAX_LINE_SIZE {
+            self.stop();
+        }
+    }
+
+    /// Goes through what it can of `input`, returns how many bytes that was.
+    fn consume(&mut self, input: &[u8]) -> usize {
+        let mut pos = 0;
+        while let Some(n) = self.advance(&input[pos..]) {
+            pos += n;
+        }
+        pos
+    }
+
+    /// Goes through the next part of `input`, returns its size or `None` when it needs more.
+    fn advance(&mut self, input: &[u8]) -> Option<usize> {
+        if input.is_empty() {
+            return None;
+        }
+
+        match self.framing {
+            Framing::Stopped => None,
+            Framing::Head => self.parse_head(input),
+            Framing::Body(left) | Framing::ChunkData(left) => {
+                let n = left.min(input.len() as u64);
+                self.framing = match (&self.framing, left - n) {
+                    (Framing::Body(_), 0) => self.next_request(),
+                    (Framing::Body(_), left) => Framing::Body(left),
+                    (_, 0) => Framing::ChunkEnd,
+                    (_, left) => Framing::ChunkData(left),
+                };
+                Some(n as usize)
+            }
+            Framing::ChunkSize => {
+                let (line, n) = line(input)?;
+                let size = line.split(|&b| b == b';').next().and_then(|size| {
+                    u64::from_str_radix(std::str::from_utf8(size).ok()?.trim(), 16).ok()
+                });
+
+                self.framing = match size {
+                    Some(0) => Framing::Trailers,
+                    Some(size) => Framing::ChunkData(size),
+                    None => return self.stop(),
+                };
+                Some(n)
+            }
+            Framing::ChunkEnd => {
+                let (line, n) = line(input)?;
+                if !line.is_empty() {
+                    return self.stop();
+                }
+
+                self.framing = Framing::ChunkSize;
+                Some(n)
+            }
+            Framing::Trailers => {
+                let (line, n) = line(input)?;
+
+                if line.is_empty() {
+                    self.framing = self.next_request();
+                }
+                Some(n)
+            }
+        }
+    }
+
+    fn parse_head(&mut self, input: &[u8]) -> Option<usize> {
+        let mut headers = vec![httparse::EMPTY_HEADER; self.max_headers];
+        let mut request = httparse::Request::new(&mut headers);
+
+        let n = match request.parse(input) {
+            Ok(httparse::Status::Complete(n)) => n,
+            Ok(httparse::Status::Partial) => return None,
+            Err(_) => return self.stop(),
+        };
+        let head = Head {
+            method: request.method.unwrap_or_default().to_string(),
+            target: request.path.unwrap_or_default().to_string(),
+            fields: request
+                .headers
+                .iter()
+                .map(|h| (h.name.to_ascii_lowercase().into_bytes(), h.value.to_vec()))
+                .collect(),
+        };
+
+        let values = |name: &'static str| {
+            head.fields
+                .iter()
+                .filter(move |(k, _)| k == name.as_bytes())
+                .map(|(_, v)| v.as_slice())
+        };
+        // The body is chunked when that's the last coding, see RFC 9112 section 6.3
+        let transfer_encoding = values("transfer-encoding").next_back().map(|value| {
+            value
+                .rsplit(|&b| b == b',')
+                .next()
+                .is_some_and(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+        });
+        let content_length = values("content-length")
+            .next()
+            .map(|value| std::str::from_utf8(value).ok()?.trim().parse::<u64>().ok());
+        self.upgrade = head.method == "CONNECT" || values("upgrade").next().is_some();
+
+        self.framing = match (transfer_encoding, content_length) {
+            (Some(true), _) => Framing::ChunkSize,
+            // hyper refuses these
+            (Some(false), _) | (None, Some(None)) => Framing::Stopped,
+            (None, Some(Some(length))) if length > 0 => Framing::Body(length),
+            (None, _) => self.next_request(),
+        };
+        self.heads.push_back(head);
+        Some(n)
+    }
+
+    fn next_request(&self) -> Framing {
+        match self.upgrade {
+            true => Framing::Stopped,
+            false => Framing::Head,
+        }
+    }
+
+    fn stop(&mut self) -> Option<usize> {
+        self.framing = Framing::Stopped;
+        self.buf = Vec::new();
+        None
+    }
+}
+
+/// The line at the start of `input` without its line ending, and the bytes it takes up.
+fn line(input: &[u8]) -> Option<(&[u8], usize)> {
+    let end = input.iter().position(|&b| b == b'\n')?;
+    let line = &input[..end];
+
+    Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1))
+}
+
+/// A client connection's stream whose requests [`HeaderOrder`] follows.
+pub struct OrderedStream<S> {
+    inner: S,
+    order: HeaderOrder,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for OrderedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.order.0.lock().unwrap().feed(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for OrderedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> HeaderFields {
+        fields
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_repeated_headers_in_wire_order() {
+        let (_, order) = HeaderOrder::wrap((), None);
+        // Byte by byte, and with bodies that look like request heads
+        let wire = "POST /a?b=1 HTTP/1.1\r\nA: 1\r\nB: 2\r\nA: 3\r\nContent-Length: 14\r\n\r\n\
+                    GET / HTTP/1.1\
+                    GET / HTTP/1.1\r\n\r\n\
+                    PUT /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\nX: y\r\n\r\n";
+        for byte in wire.bytes() {
+            order.0.lock().unwrap().feed(&[byte]);
+        }
+        let chunked = "e\r\nGET / HTTP/1.1\r\n0\r\nT: 1\r\n\r\nDELETE /d HTTP/1.1\r\n\r\n";
+        order.0.lock().unwrap().feed(chunked.as_bytes());
+
+        let headers = [("a", "1"), ("b", "2"), ("a", "3"), ("content-length", "14")];
+        assert_eq!(
+            order.take(&request("POST", "/a?b=1", &headers)),
+            fields(&headers)
+        );
+        assert_eq!(order.take(&request("GET", "/", &[])), fields(&[]));
+        let headers = [("transfer-encoding", "chunked"), ("x", "y")];
+        assert_eq!(
+            order.take(&request("PUT", "/c", &headers)),
+            fields(&headers)
+        );
+        assert_eq!(order.take(&request("DELETE", "/d", &[])), fields(&[]));
+    }
+
+    #[test]
+    fn keeps_only_cut_off_heads_and_lines() {
+        let (_, order) = HeaderOrder::wrap((), None);
+        let mut reader = order.0.lock().unwrap();
+
+        reader.feed(b"POST /a HTTP/1.1\r\nContent-Length: 10\r");
+        assert_eq!(reader.buf, b"POST /a HTTP/1.1\r\nContent-Length: 10\r");
+        reader.feed(b"\n\r\n0123");
+        assert!(reader.buf.is_empty());
+        assert_eq!(reader.framing, Framing::Body(6));
+        reader.feed(b"456789PUT /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1");
+        assert_eq!(reader.buf, b"1");
+        reader.feed(b"0\r\n0123456789abcdef");
+        assert!(reader.buf.is_empty());
+        assert_eq!(reader.framing, Framing::ChunkEnd);
+        assert_eq!(reader.heads.len(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_hyper_order_once_out_of_step() {
+        let (_, order) = HeaderOrder::wrap((), None);
+        order.0.lock().unwrap().feed(
+            b"GET /a HTTP/1.1\r\nA: 1\r\n\r\nGET /b HTTP/1.1\r\nA: 1\r\nB: 2\r\nA: 3\r\n\r\n",
+        );
+
+        let headers = [("a", "1"), ("b", "2"), ("a", "3")];
+        assert_eq!(
+            order.take(&request("GET", "/b", &headers)),
+            fields(&[("a", "1"), ("a", "3"), ("b", "2")])
+        );
+        assert_eq!(
+            order.take(&request("GET", "/b", &headers)),
+            fields(&[("a", "1"), ("a", "3"), ("b", "2")])
+        );
+    }
+
+    #[test]
+    fn stops_following_upgraded_connections() {
+        let (_, order) = HeaderOrder::wrap((), None);
+        order
+            .0
+            .lock()
+            .unwrap()
+            .feed(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\nGET /a HTTP/1.1\r\n\r\n");
+
+        assert_eq!(order.0.lock().unwrap().framing, Framing::Stopped);
+        assert_eq!(order.0.lock().unwrap().heads.len(), 1);
+    }
+}
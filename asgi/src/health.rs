@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Response, StatusCode};
+use serde_json::json;
+
+use crate::pool::WorkerPool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Health,
+    Ready,
+}
+
+/// Liveness and readiness probes answered by the front end itself, without going through
+/// Python.
+pub struct Probes {
+    health_path: Option<String>,
+    ready_path: Option<String>,
+    min_workers: usize,
+}
+
+impl Probes {
+    pub fn new(
+        health_path: Option<String>,
+        ready_path: Option<String>,
+        min_workers: usize,
+    ) -> Self {
+        Self {
+            health_path,
+            ready_path,
+            min_workers,
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> Option<Probe> {
+        if self.health_path.as_deref() == Some(path) {
+            Some(Probe::Health)
+        } else if self.ready_path.as_deref() == Some(path) {
+            Some(Probe::Ready)
+        } else {
+            None
+        }
+    }
+
+    /// The server is alive as long as it answers; it's ready once enough of the running
+    /// workers answered their last heartbeat with the app's lifespan startup done.
+    pub fn answer(&self, probe: Probe, workers: &[Arc<WorkerPool>]) -> Response<Full<Bytes>> {
+        match probe {
+            Probe::Health => response(StatusCode::OK, json!({ "status": "ok" })),
+            Probe::Ready => {
+                let healthy = workers
+                    .iter()
+                    .filter(|worker| {
+                        worker
+                            .last_heartbeat()
+                            .is_some_and(|heartbeat| heartbeat.started)
+                    })
+                    .count();
+
+                let (status, state) = match healthy >= self.min_workers {
+                    true => (StatusCode::OK, "ready"),
+                    false => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+                };
+
+                response(
+                    status,
+                    json!({
+                        "status": state,
+                        "workers": healthy,
+                        "required": self.min_workers,
+                    }),
+                )
+            }
+        }
+    }
+}
+
+fn response(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use messages::codec::{ClientCodec, WorkerCodec, DEFAULT_MAX_FRAME_SIZE};
+    use messages::types::{ASGIMessages, Capabilities, Heartbeat, WorkerRequest};
+    use tokio::net::UnixStream;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::upstream::WorkerConnection;
+
+    #[test]
+    fn not_ready_without_enough_workers() {
+        let probes = Probes::new(
+            Some("/__ferricorn/health".to_string()),
+            Some("/__ferricorn/ready".to_string()),
+            1,
+        );
+
+        assert_eq!(probes.matches("/__ferricorn/health"), Some(Probe::Health));
+        assert_eq!(probes.matches("/__ferricorn/ready"), Some(Probe::Ready));
+        assert_eq!(probes.matches("/"), None);
+
+        let gone = [Arc::new(WorkerPool::new(
+            0,
+            "/tmp/ferricorn_no_such_worker".to_string(),
+            1024,
+            1,
+        ))];
+        let health = probes.answer(Probe::Health, &gone);
+        let ready = probes.answer(Probe::Ready, &gone);
+
+        assert_eq!(health.status(), StatusCode::OK);
+        assert_eq!(ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn counts_only_workers_done_starting() {
+        let probes = Probes::new(None, Some("/ready".to_string()), 1);
+        let (stream, worker) = UnixStream::pair().unwrap();
+        let pool = Arc::new(WorkerPool::new(0, "/nonexistent.sock".to_string(), 1024, 1));
+        pool.put(WorkerConnection {
+            framed: Framed::new(stream, ClientCodec::new(DEFAULT_MAX_FRAME_SIZE)),
+            capabilities: Capabilities::empty(),
+        });
+
+        // Answers a heartbeat before the app's startup finished, then one after
+        tokio::spawn(async move {
+            let mut worker = Framed::new(worker, WorkerCodec::new(DEFAULT_MAX_FRAME_SIZE));
+            for started in [false, true] {
+                let Some(Ok(WorkerRequest::Heartbeat)) = worker.next().await else {
+                    panic!("expected a heartbeat");
+                };
+                let heartbeat = Heartbeat {
+                    pid: 1,
+                    queue_depth: 0,
+                    rss_bytes: None,
+                    started,
+                };
+                worker
+                    .send(ASGIMessages::Heartbeat(heartbeat))
+                    .await
+                    .unwrap();
+            }
+        });
+        let workers = [pool];
+
+        workers[0].heartbeat().await.unwrap();
+        let starting = probes.answer(Probe::Ready, &workers);
+        workers[0].heartbeat().await.unwrap();
+        let started = probes.answer(Probe::Ready, &workers);
+
+        assert_eq!(starting.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(started.status(), StatusCode::OK);
+    }
+}
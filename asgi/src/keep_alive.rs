@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Follows the requests made on one client connection, to close it once it has been idle
+/// for too long or has served its share of requests.
+pub struct KeepAlive {
+    timeout: Duration,
+    max_requests: Option<usize>,
+    served: AtomicUsize,
+    active: AtomicUsize,
+    /// When the last response completed, the first head is hyper's header read timeout's
+    last_active: Mutex<Option<Instant>>,
+}
+
+impl KeepAlive {
+    pub fn new(timeout: Duration, max_requests: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            timeout,
+            max_requests,
+            served: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            last_active: Mutex::new(None),
+        })
+    }
+
+    /// Counts a request as active until the returned guard is dropped, along with whether
+    /// it is the last one the connection may serve.
+    pub fn start_request(self: &Arc<Self>) -> (Active, bool) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let served = self.served.fetch_add(1, Ordering::Relaxed) + 1;
+        let last = self.max_requests.is_some_and(|max| served >= max);
+
+        (Active(Arc::clone(self)), last)
+    }
+
+    /// When the connection should be closed if nothing happens on it until then, `None`
+    /// while a request is being handled or before the first response completed.
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        if self.active.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+
+        self.last_active
+            .lock()
+            .unwrap()
+            .map(|last_active| last_active + self.timeout)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+pub struct Active(Arc<KeepAlive>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        *self.0.last_active.lock().unwrap() = Some(Instant::now());
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_the_last_request_and_idles_only_between_requests() {
+        let keep_alive = KeepAlive::new(Duration::from_secs(5), Some(2));
+        assert!(keep_alive.idle_deadline().is_none());
+
+        let (first, last) = keep_alive.start_request();
+        assert!(!last);
+        assert!(keep_alive.idle_deadline().is_none());
+        drop(first);
+
+        let deadline = keep_alive.idle_deadline().unwrap();
+        assert!(deadline > Instant::now());
+
+        let (_second, last) = keep_alive.start_request();
+        assert!(last);
+    }
+}
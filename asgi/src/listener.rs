@@ -0,0 +1,431 @@
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{env, fs, process};
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::args::Arguments;
+
+const DEFAULT_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3100);
+// First file descriptor passed by systemd socket activation, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+const ADMIN_UDS_MODE: u32 = 0o600;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Stream, Option<SocketAddr>)>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (Stream::Tcp(stream), Some(addr))),
+            Listener::Unix(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| (Stream::Unix(stream), None)),
+        }
+    }
+}
+
+/// Every socket the server accepts connections on, regardless of how it was obtained.
+pub struct Listeners {
+    listeners: Vec<Listener>,
+    uds_paths: Vec<PathBuf>,
+    next: usize,
+}
+
+impl Listeners {
+    /// Listens on `inherited` sockets, see [`systemd_fds`], and on those given on the command
+    /// line.
+    pub fn bind(cli: &Arguments, inherited: &[RawFd]) -> io::Result<Self> {
+        let mut listeners = Self {
+            listeners: Vec::new(),
+            uds_paths: Vec::new(),
+            next: 0,
+        };
+
+        let mut fds: Vec<RawFd> = Vec::new();
+        for &fd in inherited.iter().chain(&cli.fd) {
+            // Each fd can only be owned once, even if passed both by systemd and --fd
+            if !fds.contains(&fd) {
+                fds.push(fd);
+            }
+        }
+
+        for fd in fds {
+            listeners.listeners.push(from_fd(fd)?);
+        }
+
+        for path in &cli.uds {
+            let owner = (cli.uds_owner, cli.uds_group);
+            let listener = bind_uds(path, cli.backlog, cli.uds_mode, owner)?;
+            listeners.uds_paths.push(path.clone());
+            listeners.listeners.push(listener);
+        }
+
+        for addr in &cli.bind {
+            listeners.listeners.push(bind_tcp(*addr, cli.backlog)?);
+        }
+
+        if listeners.listeners.is_empty() {
+            listeners
+                .listeners
+                .push(bind_tcp(SocketAddr::from(DEFAULT_ADDR), cli.backlog)?);
+        }
+
+        Ok(listeners)
+    }
+
+    /// Sockets for the admin API, empty when it isn't enabled. Its Unix socket is only
+    /// accessible to the user the server runs as.
+    pub fn bind_admin(cli: &Arguments) -> io::Result<Self> {
+        let mut listeners = Self {
+            listeners: Vec::new(),
+            uds_paths: Vec::new(),
+            next: 0,
+        };
+
+        if let Some(path) = &cli.admin_uds {
+            let listener = bind_uds(path, cli.backlog, Some(ADMIN_UDS_MODE), (None, None))?;
+            listeners.uds_paths.push(path.clone());
+            listeners.listeners.push(listener);
+        }
+
+        if let Some(addr) = cli.admin_bind {
+            listeners.listeners.push(bind_tcp(addr, cli.backlog)?);
+        }
+
+        Ok(listeners)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Waits for a connection on any of the listeners, starting from a different one on
+    /// each call so a busy socket can't starve the others.
+    pub async fn accept(&mut self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        let start = self.next;
+        self.next = (self.next + 1) % self.listeners.len();
+
+        poll_fn(|cx| {
+            let count = self.listeners.len();
+
+            for i in 0..count {
+                if let Poll::Ready(accepted) = self.listeners[(start + i) % count].poll_accept(cx) {
+                    return Poll::Ready(accepted);
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        self.listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(l) => match l.local_addr() {
+                    Ok(addr) => format!("http://{}", addr),
+                    Err(_) => "http://(unknown)".to_string(),
+                },
+                Listener::Unix(l) => match l.local_addr() {
+                    Ok(addr) => match addr.as_pathname() {
+                        Some(path) => format!("unix:{}", path.display()),
+                        None => "unix:(unnamed)".to_string(),
+                    },
+                    Err(_) => "unix:(unknown)".to_string(),
+                },
+            })
+            .collect()
+    }
+}
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        for path in &self.uds_paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Takes the sockets passed by systemd socket activation, clearing the variables so the
+/// worker processes don't think the sockets were passed to them. Changing the environment
+/// isn't thread safe, so this must run before the runtime starts its threads.
+pub fn systemd_fds() -> Vec<RawFd> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    listen_fds(pid.as_deref(), count.as_deref(), process::id())
+}
+
+fn listen_fds(pid: Option<&str>, count: Option<&str>, own_pid: u32) -> Vec<RawFd> {
+    let for_us = pid
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == own_pid);
+    let count = count
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    if !for_us {
+        return Vec::new();
+    }
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect()
+}
+
+fn from_fd(fd: RawFd) -> io::Result<Listener> {
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+
+    if socket.r#type()? != Type::STREAM || !is_listening(fd)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("File descriptor {} is not a listening stream socket", fd),
+        ));
+    }
+
+    socket.set_cloexec(true)?;
+    socket.set_nonblocking(true)?;
+
+    let addr = socket.local_addr()?;
+
+    if addr.is_unix() {
+        Ok(Listener::Unix(UnixListener::from_std(socket.into())?))
+    } else if addr.as_socket().is_some() {
+        Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("File descriptor {} is not a TCP or Unix socket", fd),
+        ))
+    }
+}
+
+/// Whether `listen` was called on the socket, socket2 only exposes SO_ACCEPTCONN on Linux.
+fn is_listening(fd: RawFd) -> io::Result<bool> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match result {
+        0 => Ok(value != 0),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, backlog: i32) -> io::Result<Listener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+
+    Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+}
+
+/// Binds a Unix socket at `path` with its `mode` and `owner` already applied: it's bound in
+/// a directory only this user can enter and moved into place once ready, so that nobody can
+/// connect while it still has the umask's permissions.
+fn bind_uds(
+    path: &Path,
+    backlog: i32,
+    mode: Option<u32>,
+    owner: (Option<u32>, Option<u32>),
+) -> io::Result<Listener> {
+    match fs::symlink_metadata(path) {
+        // A socket left behind by a previous run would make bind fail
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ))
+        }
+        Err(_) => (),
+    }
+
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't a file path", path.display()),
+        )
+    })?;
+    // Next to the socket so that moving it stays within the file system
+    let private = path.with_file_name(format!(".{}.{}.tmp", name.to_string_lossy(), process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+
+    let bound = bind_staged(&staged, path, backlog, mode, owner);
+    let _ = fs::remove_dir_all(&private);
+
+    Ok(Listener::Unix(UnixListener::from_std(bound?.into())?))
+}
+
+/// Binds and sets up the socket at `staged`, then moves it to `path`.
+fn bind_staged(
+    staged: &Path,
+    path: &Path,
+    backlog: i32,
+    mode: Option<u32>,
+    owner: (Option<u32>, Option<u32>),
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::unix(staged)?)?;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(staged, fs::Permissions::from_mode(mode))?;
+    }
+    if owner.0.is_some() || owner.1.is_some() {
+        chown(staged, owner.0, owner.1)?;
+    }
+
+    socket.listen(backlog)?;
+    fs::rename(staged, path)?;
+
+    Ok(socket)
+}
+
+/// A connection accepted from any of the [`Listeners`].
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::IntoRawFd;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_unix_sockets_with_their_mode_already_set() {
+        let dir = env::temp_dir().join(format!("ferricorn_test_uds_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("server.sock");
+
+        let listener = bind_uds(&path, 16, Some(0o600), (None, None)).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // Only the socket is left in the directory
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Reachable at its final path
+        let connected = UnixStream::connect(&path);
+        let Listener::Unix(listener) = listener else {
+            panic!("Expected a Unix listener");
+        };
+        let (accepted, connected) = tokio::join!(listener.accept(), connected);
+        accepted.unwrap();
+        connected.unwrap();
+
+        // Replaces a stale socket, not other files
+        drop(listener);
+        assert!(bind_uds(&path, 16, None, (None, None)).is_ok());
+        let file = dir.join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(bind_uds(&file, 16, None, (None, None)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_takes_sockets_passed_to_this_process() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), vec![3, 4]);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), Vec::<RawFd>::new());
+        assert_eq!(listen_fds(None, Some("2"), 42), Vec::<RawFd>::new());
+        assert_eq!(listen_fds(Some("42"), None, 42), Vec::<RawFd>::new());
+        assert_eq!(listen_fds(Some("42"), Some("two"), 42), Vec::<RawFd>::new());
+    }
+
+    #[test]
+    fn rejects_fds_that_are_not_listening_stream_sockets() {
+        let unbound = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        assert!(from_fd(unbound.into_raw_fd()).is_err());
+
+        let datagram = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        datagram
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+            .unwrap();
+        assert!(from_fd(datagram.into_raw_fd()).is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_listening_stream_sockets() {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+            .unwrap();
+        socket.listen(1).unwrap();
+
+        assert!(matches!(
+            from_fd(socket.into_raw_fd()),
+            Ok(Listener::Tcp(_))
+        ));
+    }
+}
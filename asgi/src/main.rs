@@ -1,184 +1,978 @@
-use std::collections::HashMap;
-use std::io::{stderr, stdout};
 use std::net::SocketAddr;
-use std::process::{Command, Stdio};
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, LazyLock};
-use std::{env, os};
-
-use http_body_util::{BodyExt, Full};
-use hyper::body::{Bytes, Incoming};
-use hyper::header::{HeaderName, HeaderValue};
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use access_log::{AccessLog, Entry};
+use args::Arguments;
+use autoscale::Autoscaler;
+use balancer::Balancer;
+use body::ResponseBody;
+use clap::Parser;
+use compression::Compression;
+use early_hints::EarlyHints;
+use futures_util::{SinkExt, StreamExt};
+use header_order::{HeaderFields, HeaderOrder};
+use health::Probes;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, EXPECT, RETRY_AFTER};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{HeaderMap, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
-use messages::types::{ASGIMessages, HttpMethod, ParsedRequest, Uri};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, UnixStream};
-use tokio::sync::Mutex;
+use hyper::{HeaderMap, Request, Response, StatusCode, Version};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use keep_alive::KeepAlive;
+use listener::Listeners;
+use messages::codec::CodecError;
+use messages::types::{
+    ASGIMessages, HttpMethod, ParsedRequest, TraceContext, Uri, WorkerRequest, RETRY_AFTER_SECONDS,
+};
+use metrics::Metrics;
+use observability::logging;
+use observability::telemetry::{self, Telemetry};
+use pool::{InFlight, WorkerPool};
+use static_files::StaticFiles;
+use supervisor::Supervisor;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument, Span};
+
+pub mod access_log;
+pub mod admin;
+pub mod args;
+pub mod autoscale;
+pub mod balancer;
+pub mod body;
+pub mod compression;
+pub mod early_hints;
+pub mod header_order;
+pub mod health;
+pub mod keep_alive;
+pub mod listener;
+pub mod metrics;
+pub mod pool;
+pub mod proxy;
+pub mod static_files;
+pub mod supervisor;
+pub mod upstream;
+
+// W3C trace context headers
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+/// Pause before accepting again when the process ran out of file descriptors, for open
+/// connections to close some.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 async fn process_request(
     req: Request<hyper::body::Incoming>,
-    sock_file: String,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    let headers: HashMap<String, String> = req
+    (pool, in_flight): (Arc<WorkerPool>, InFlight),
+    cli: &Arguments,
+    peer: Option<SocketAddr>,
+    request_id: String,
+    headers: HeaderFields,
+    hints: &EarlyHints,
+) -> Result<Response<ResponseBody>, hyper::Error> {
+    let too_large = req
         .headers()
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
-        .collect();
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|length| length > cli.limit_request_body);
+    if too_large {
+        return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
 
-    let method = HttpMethod::try_from(req.method().to_string()).unwrap();
+    let method = match HttpMethod::try_from(req.method().to_string()) {
+        Ok(method) => method,
+        Err(_) => return Ok(error_response(StatusCode::BAD_REQUEST)),
+    };
     let scheme = req.uri().scheme().map(|s| s.to_string());
     let path = req.uri().path().to_string();
     let query_string = req.uri().query().map(|str| str.to_string());
 
     let uri = Uri::new(scheme, path, query_string);
-    let body = req.collect().await.unwrap().to_bytes().to_vec();
-
-    let request = ParsedRequest::new(headers, method, body, uri);
-
-    let mut stream = UnixStream::connect(sock_file).await.unwrap();
-
-    let payload = bincode::serialize(&request).unwrap();
-    dbg!("Sending payload", &request);
-    stream
-        .write_all(&(payload.len() as u32).to_be_bytes())
+    let root_path = proxy::root_path(cli, peer, req.headers());
+    // The worker's spans hang off this request's span, or off the caller's trace when
+    // spans aren't exported
+    let trace_context = match telemetry::trace_context(&Span::current()) {
+        context if context.is_empty() => incoming_trace_context(req.headers()),
+        context => context,
+    };
+    // Reading the body is what makes hyper send 100 Continue, so a client that waits for it
+    // only sends the body once the app asks for it, and not at all when the app answers first
+    let expects_continue = req.version() == Version::HTTP_11
+        && req
+            .headers()
+            .get(EXPECT)
+            .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    let (body, mut deferred_body) = match expects_continue {
+        true => (Vec::new(), Some(req.into_body())),
+        false => match read_body(req.into_body(), cli.limit_request_body).await {
+            Ok(body) => (body, None),
+            Err(status) => return Ok(error_response(status)),
+        },
+    };
+
+    let mut request = ParsedRequest::new(
+        headers,
+        method,
+        body,
+        uri,
+        root_path,
+        request_id,
+        trace_context,
+    );
+    request.body_deferred = deferred_body.is_some();
+
+    let worker = pool.id();
+    let mut connection = match pool.get().await {
+        Ok(connection) => connection,
+        Err(err) => {
+            error!(worker, error = %err, "Failed to connect to worker");
+            return Ok(error_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    debug!(
+        worker,
+        method = %request.method,
+        path = request.uri.path(),
+        "Sending request to worker"
+    );
+    match connection
+        .framed
+        .send(WorkerRequest::Http(Box::new(request)))
         .await
-        .unwrap();
-    stream.write_all(&payload).await.unwrap();
-    dbg!(" payload sent  to worker");
+    {
+        Ok(()) => (),
+        Err(CodecError::FrameTooLarge { .. }) => {
+            return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE))
+        }
+        Err(err) => {
+            error!(worker, error = %err, "Failed to send request to worker");
+            return Ok(error_response(StatusCode::BAD_GATEWAY));
+        }
+    }
 
     let mut status_code = StatusCode::INTERNAL_SERVER_ERROR;
     let mut headers = HeaderMap::new();
-
-    loop {
-        dbg!("waiting response");
-        let mut buf_len = [0u8; 4];
-        stream.read_exact(&mut buf_len).await.unwrap();
-
-        let lenght = u32::from_be_bytes(buf_len) as usize;
-        let mut payload_buf = vec![0u8; lenght];
-
-        stream.read_exact(&mut payload_buf).await.unwrap();
-
-        let msg = bincode::deserialize::<ASGIMessages>(&payload_buf).unwrap();
+    let mut trailers = false;
+    let mut started = false;
+
+    let body = loop {
+        let msg = match connection.framed.next().await {
+            Some(Ok(msg)) => msg,
+            Some(Err(err)) => {
+                error!(worker, error = %err, "Invalid response from worker");
+                return Ok(error_response(StatusCode::BAD_GATEWAY));
+            }
+            None => {
+                error!(worker, "Worker closed the connection mid-response");
+                return Ok(error_response(StatusCode::BAD_GATEWAY));
+            }
+        };
 
         match msg {
+            ASGIMessages::ReadBody => {
+                let Some(incoming) = deferred_body.take() else {
+                    error!(worker, "Worker asked for a request body it already has");
+                    return Ok(error_response(StatusCode::BAD_GATEWAY));
+                };
+                // The worker is left waiting on failures, dropping the connection ends that
+                let body = match read_body(incoming, cli.limit_request_body).await {
+                    Ok(body) => body,
+                    Err(status) => return Ok(error_response(status)),
+                };
+                match connection.framed.send(WorkerRequest::Body(body)).await {
+                    Ok(()) => (),
+                    Err(CodecError::FrameTooLarge { .. }) => {
+                        return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE))
+                    }
+                    Err(err) => {
+                        error!(worker, error = %err, "Failed to send the request body to worker");
+                        return Ok(error_response(StatusCode::BAD_GATEWAY));
+                    }
+                }
+            }
+            ASGIMessages::HttpResponseEarlyHint(hint) => {
+                if hints.send(&hint.links) {
+                    trace!(worker, links = hint.links.len(), "Early hints sent");
+                }
+            }
             ASGIMessages::HttpResponseStart(http_response_start) => {
-                dbg!(&http_response_start);
-                status_code = StatusCode::from_u16(http_response_start.status).unwrap();
+                if std::mem::replace(&mut started, true) {
+                    error!(worker, "Worker started the response twice");
+                    return Ok(error_response(StatusCode::BAD_GATEWAY));
+                }
+                trace!(
+                    worker,
+                    status = http_response_start.status,
+                    "Response started"
+                );
+                status_code = match StatusCode::from_u16(http_response_start.status) {
+                    Ok(status) => status,
+                    Err(err) => {
+                        error!(worker, error = %err, "Worker sent an invalid status");
+                        return Ok(error_response(StatusCode::BAD_GATEWAY));
+                    }
+                };
                 for (n, v) in http_response_start.headers {
-                    headers.insert(
-                        HeaderName::from_bytes(&n).unwrap(),
-                        HeaderValue::from_bytes(&v).unwrap(),
-                    );
+                    match (HeaderName::from_bytes(&n), HeaderValue::from_bytes(&v)) {
+                        (Ok(name), Ok(value)) => {
+                            headers.append(name, value);
+                        }
+                        _ => {
+                            error!(
+                                worker,
+                                header = %String::from_utf8_lossy(&n),
+                                "Worker sent an invalid response header"
+                            );
+                            return Ok(error_response(StatusCode::BAD_GATEWAY));
+                        }
+                    }
+                }
+
+                // Trailers only go out with a chunked body, and only to clients that sent
+                // TE: trailers and for the fields the app listed in a Trailer header
+                trailers = http_response_start.trailers;
+                if trailers {
+                    headers.remove(CONTENT_LENGTH);
                 }
             }
             ASGIMessages::HttpResponseBody(http_response_body) => {
-                let mut builder = Response::builder();
-                let headers_resp = builder.headers_mut().unwrap();
+                let body = match http_response_body.more_body || trailers {
+                    true => body::from_worker(
+                        connection,
+                        Arc::clone(&pool),
+                        ASGIMessages::HttpResponseBody(http_response_body),
+                        None,
+                        trailers,
+                        in_flight,
+                    ),
+                    false => {
+                        pool.put(connection);
+                        body::full(http_response_body.body)
+                    }
+                };
 
-                for (k, v) in headers.clone() {
-                    if let (Some(key), value) = (k, v) {
-                        headers_resp.insert(key, value);
+                break body;
+            }
+            ASGIMessages::HttpResponseFile(file) => {
+                // Opened before the response starts so that a missing file still gets a
+                // proper error response
+                let opened = match body::OpenedFile::open(&file).await {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        error!(
+                            worker,
+                            path = %file.path.display(),
+                            error = %err,
+                            "Failed to open the file to send"
+                        );
+                        // Otherwise the rest of the response is still on its way
+                        if !file.more_body && !trailers {
+                            pool.put(connection);
+                        }
+                        return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR));
                     }
+                };
+
+                // A whole body from a file has a known length
+                if !file.more_body && !trailers && !headers.contains_key(CONTENT_LENGTH) {
+                    headers.insert(CONTENT_LENGTH, HeaderValue::from(opened.length()));
                 }
 
-                return Ok(builder
-                    .status(status_code)
-                    .body(Full::new(Bytes::from(http_response_body.body)))
-                    .unwrap());
+                break body::from_worker(
+                    connection,
+                    Arc::clone(&pool),
+                    ASGIMessages::HttpResponseFile(file),
+                    Some(opened),
+                    trailers,
+                    in_flight,
+                );
+            }
+            ASGIMessages::HttpResponseTrailers(_) | ASGIMessages::Heartbeat(_) => {
+                error!(
+                    worker,
+                    "Worker sent an unexpected message before the response body"
+                );
+                return Ok(error_response(StatusCode::BAD_GATEWAY));
             }
         }
+    };
+
+    let mut builder = Response::builder();
+    builder.headers_mut().unwrap().extend(headers);
+
+    Ok(builder.status(status_code).body(body).unwrap())
+}
+
+fn incoming_trace_context(headers: &HeaderMap) -> TraceContext {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    TraceContext {
+        traceparent: header(TRACEPARENT),
+        tracestate: header(TRACESTATE),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args: Vec<String> = env::args().collect();
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3100));
-    let listener = TcpListener::bind(addr).await?;
-    let worker_count = args.get(2).map_or("1", |v| v).parse::<usize>().unwrap();
+/// Reads the whole request body, failing with the status to answer when it is over `limit`
+/// or the client stopped sending it halfway.
+async fn read_body(body: Incoming, limit: usize) -> Result<Vec<u8>, StatusCode> {
+    match Limited::new(body, limit).collect().await {
+        Ok(collected) => Ok(collected.to_bytes().to_vec()),
+        Err(err) if err.is::<LengthLimitError>() => Err(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
 
-    let sock_file = "/tmp/ferricorn_worker";
-    let workers: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+fn overloaded_response() -> Response<ResponseBody> {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static(RETRY_AFTER_SECONDS));
+    response
+}
 
-    for i in 0..worker_count {
-        let module = args[1].clone();
-        let sock_file = format!("{}_{}", sock_file, i);
-        let workers = Arc::clone(&workers);
+fn error_response(status: StatusCode) -> Response<ResponseBody> {
+    let mut response = Response::new(body::full(status.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = status;
+    response
+}
 
-        tokio::spawn(async move {
-            let mut child = Command::new("cargo")
-                .args([
-                    "run", "-p", "worker", "--", "--module", &module, "--sock", &sock_file,
-                ])
-                .stdout(stdout())
-                .stderr(stderr())
-                .spawn()
-                .expect("couldn't start worker");
-
-            dbg!(child.id());
-            {
-                let mut workers = workers.lock().await;
-                workers.push(sock_file.clone());
-            }
-            let exit_code = child.wait().expect("worker not running");
-            dbg!("child process exited with  {}", exit_code);
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Before the runtime starts any thread, see listener::systemd_fds
+    let inherited = listener::systemd_fds();
 
-            {
-                let mut workers = workers.lock().await;
-                let position = workers
-                    .iter()
-                    .position(|w| *w == sock_file)
-                    .expect("worker file not found in list of actrive workers");
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(inherited))
+}
 
-                workers.remove(position);
-            }
-        });
+async fn run(inherited: Vec<RawFd>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Arc::new(Arguments::parse());
+    let telemetry = match &cli.otlp_endpoint {
+        Some(endpoint) => Some(Telemetry::new(endpoint, "ferricorn")?),
+        None => None,
+    };
+    logging::init(cli.log_level, cli.log_format, telemetry.as_ref());
+
+    let bounds = match cli.autoscale_max {
+        Some(max) if cli.autoscale_min == 0 || cli.autoscale_min > max => {
+            return Err(format!(
+                "--autoscale-min must be between 1 and --autoscale-max ({})",
+                max
+            )
+            .into());
+        }
+        Some(max) => cli.autoscale_min..=max,
+        // Signals and the admin API can't start more, one scale request shouldn't be
+        // able to start thousands of processes
+        None => 1..=cli.workers.max(supervisor::default_max_workers()),
+    };
+
+    let mut listeners = Listeners::bind(&cli, &inherited)?;
+    let worker_count = cli.workers.clamp(*bounds.start(), *bounds.end());
+
+    let metrics = Arc::new(Metrics::new());
+    let supervisor = Supervisor::new(Arc::clone(&cli), Arc::clone(&metrics), bounds.clone());
+    let workers = supervisor.workers();
+
+    for _ in 0..worker_count {
+        supervisor.spawn()?;
+    }
+
+    tokio::spawn(supervisor::poll_heartbeats(Arc::clone(&workers)));
+
+    if cli.autoscale_max.is_some() {
+        info!(
+            min = bounds.start(),
+            max = bounds.end(),
+            "Autoscaling workers"
+        );
+        tokio::spawn(autoscale::run(
+            Arc::clone(&supervisor),
+            Autoscaler::new(bounds),
+        ));
+    }
+
+    for address in listeners.describe() {
+        info!("Listening on {}", address);
+    }
+
+    if let Some(addr) = cli.metrics_bind {
+        let listener = TcpListener::bind(addr).await?;
+        info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        tokio::spawn(metrics::serve(
+            listener,
+            Arc::clone(&metrics),
+            Arc::clone(&workers),
+        ));
     }
 
+    let admin_listeners = Listeners::bind_admin(&cli)?;
+    if !admin_listeners.is_empty() {
+        for address in admin_listeners.describe() {
+            info!("Serving the admin API on {}", address);
+        }
+        tokio::spawn(admin::serve(admin_listeners, Arc::clone(&supervisor)));
+    }
+
+    let access_log = match cli.no_access_log {
+        true => None,
+        false => Some(Arc::new(AccessLog::open(
+            cli.access_log_format,
+            &cli.access_log,
+        )?)),
+    };
+
+    let balancer = Arc::new(Balancer::new(cli.balancer, cli.limit_worker_concurrency));
+    let static_files = Arc::new(StaticFiles::new(cli.static_mounts.clone()));
+    let compression = Arc::new(Compression::new(
+        cli.compression.clone(),
+        cli.compression_min_size,
+        cli.compression_types.clone(),
+    ));
+    let probes = Arc::new(Probes::new(
+        cli.health_path.clone(),
+        cli.ready_path.clone(),
+        cli.ready_min_workers,
+    ));
+    let concurrency = cli
+        .limit_concurrency
+        .map(|limit| Arc::new(Semaphore::new(limit)));
+    let mut signal_terminate = signal(SignalKind::terminate())?;
+    let mut signal_interrupt = signal(SignalKind::interrupt())?;
+    let mut signal_reopen = signal(SignalKind::user_defined1())?;
+    // Same as gunicorn: TTIN adds a worker, TTOU removes one
+    let mut signal_add_worker = signal(SignalKind::from_raw(libc::SIGTTIN))?;
+    let mut signal_remove_worker = signal(SignalKind::from_raw(libc::SIGTTOU))?;
+
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = tokio::select! {
+            accepted = listeners.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(error = %err, "Failed to accept connection");
+                    // Out of file descriptors, retrying right away would fail the same way
+                    if matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+                        sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                    continue;
+                }
+            },
+            _ = signal_terminate.recv() => break,
+            _ = signal_interrupt.recv() => break,
+            _ = signal_reopen.recv() => {
+                if let Some(access_log) = &access_log {
+                    if let Err(err) = access_log.reopen() {
+                        error!(error = %err, "Failed to reopen the access log");
+                    }
+                }
+                continue;
+            }
+            _ = signal_add_worker.recv() => {
+                if let Err(err) = supervisor.add().await {
+                    warn!(error = %err, "Failed to add a worker");
+                }
+                continue;
+            }
+            _ = signal_remove_worker.recv() => {
+                if let Err(err) = supervisor.remove().await {
+                    warn!(error = %err, "Failed to remove a worker");
+                }
+                continue;
+            }
+        };
 
+        let (stream, header_order) = HeaderOrder::wrap(stream, cli.limit_request_headers);
+        let (stream, hints) = EarlyHints::wrap(stream);
         let io = TokioIo::new(stream);
         let workers = Arc::clone(&workers);
+        let cli = Arc::clone(&cli);
+        let balancer = Arc::clone(&balancer);
+        let probes = Arc::clone(&probes);
+        let static_files = Arc::clone(&static_files);
+        let compression = Arc::clone(&compression);
+        let hints = hints.clone();
+        let concurrency = concurrency.clone();
+        let access_log = access_log.clone();
+        let metrics = Arc::clone(&metrics);
+        let keep_alive = KeepAlive::new(
+            Duration::from_secs(cli.timeout_keep_alive),
+            cli.keep_alive_requests,
+        );
+
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(Duration::from_secs(cli.timeout_header_read));
+        if let Some(max) = cli.limit_request_headers {
+            builder.max_headers(max);
+        }
+        if let Some(size) = cli.limit_request_header_size {
+            builder.max_buf_size(size as usize);
+        }
 
         tokio::task::spawn(async move {
+            let connection_keep_alive = Arc::clone(&keep_alive);
             let service = service_fn(move |req: Request<Incoming>| {
                 let inner_workers = Arc::clone(&workers);
+                let cli = Arc::clone(&cli);
+                let balancer = Arc::clone(&balancer);
+                let probes = Arc::clone(&probes);
+                let static_files = Arc::clone(&static_files);
+                let compression = Arc::clone(&compression);
+                let hints = hints.clone();
+                let header_order = header_order.clone();
+                let concurrency = concurrency.clone();
+                let keep_alive = Arc::clone(&keep_alive);
+                let access_log = access_log.clone();
+                let metrics = Arc::clone(&metrics);
+
+                let method = req.method().clone();
+                let entry = Entry::new(&req, peer);
+                let span = info_span!(
+                    target: telemetry::TARGET,
+                    "HTTP request",
+                    otel.name = %method,
+                    otel.kind = "server",
+                    otel.status_code = Empty,
+                    http.request.method = %method,
+                    url.path = req.uri().path(),
+                    http.response.status_code = Empty,
+                    request_id = entry.request_id(),
+                );
+                telemetry::set_parent(&span, &incoming_trace_context(req.headers()));
+
+                let handled = async move {
+                    let mut entry = entry;
+                    let started = Instant::now();
+                    // Taken for every request, the order of the next ones depends on it
+                    let fields = header_order.take(&req);
+                    let (active, last) = keep_alive.start_request();
+                    let in_flight = metrics.start_request();
+                    // HTTP/1.0 clients don't expect informational responses
+                    hints.open(req.version() == Version::HTTP_11);
+
+                    let mut worker = None;
+                    // Held until the response body is done, streamed bodies count too
+                    let mut permit = None;
+
+                    let mut response = match probes.matches(req.uri().path()) {
+                        // Probes don't count towards the concurrency limit, an overloaded
+                        // server is still alive
+                        Some(probe) => {
+                            let workers = inner_workers.lock().await.clone();
+                            probes.answer(probe, &workers).map(body::boxed)
+                        }
+                        None => match static_files.serve(&req).await {
+                            Some(response) => response,
+                            None => {
+                                let acquired =
+                                    concurrency.map(Semaphore::try_acquire_owned).transpose();
+                                let picked = balancer.pick(&inner_workers.lock().await);
+
+                                match (acquired, picked) {
+                                    (Ok(acquired), Some(picked)) => {
+                                        permit = acquired;
+                                        worker = Some(picked.0.id());
+                                        let request_id = entry.request_id().to_string();
+                                        let encoding = compression.negotiate(&req);
+                                        let response = process_request(
+                                            req, picked, &cli, peer, request_id, fields, &hints,
+                                        )
+                                        .await?;
+                                        match encoding {
+                                            Some(encoding) => {
+                                                compression.apply(encoding, response).await
+                                            }
+                                            None => response,
+                                        }
+                                    }
+                                    _ => overloaded_response(),
+                                }
+                            }
+                        },
+                    };
+                    hints.close();
+                    if last {
+                        response
+                            .headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                    }
 
-                async move {
-                    let sock_file = round_robin(&inner_workers).await;
-                    let response_result = process_request(req, sock_file.to_string()).await;
-                    match response_result {
-                        Ok(response) => Ok(response),
-                        Err(err) => Err(err),
+                    let span = Span::current();
+                    span.record("http.response.status_code", response.status().as_u16());
+                    if response.status().is_server_error() {
+                        span.record("otel.status_code", "ERROR");
                     }
-                }
+
+                    // Streamed bodies are still on their way, the request is done when
+                    // they are
+                    let status = response.status().as_u16();
+                    let response = response.map(|body| {
+                        body::observed(body, move |bytes| {
+                            drop(permit);
+                            drop(active);
+                            drop(in_flight);
+                            metrics.observe_request(&method, status, started.elapsed());
+                            if let Some(access_log) = access_log {
+                                entry.finish(status, bytes, worker);
+                                access_log.log(&entry);
+                            }
+                        })
+                    });
+
+                    Ok::<_, hyper::Error>(response)
+                };
+
+                handled.instrument(span)
             });
 
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                eprintln!("Error serving connection: {:?}", err);
+            let keep_alive = connection_keep_alive;
+            let connection = builder.serve_connection(io, service);
+            tokio::pin!(connection);
+            let mut closing = false;
+
+            loop {
+                let deadline = keep_alive
+                    .idle_deadline()
+                    .unwrap_or_else(|| Instant::now() + keep_alive.timeout());
+
+                tokio::select! {
+                    served = connection.as_mut() => {
+                        if let Err(err) = served {
+                            warn!(error = %err, "Error serving connection");
+                        }
+                        break;
+                    }
+                    _ = sleep_until(deadline), if !closing => {
+                        // A request may have come and gone while sleeping
+                        if keep_alive.idle_deadline().is_some_and(|idle| idle <= Instant::now()) {
+                            connection.as_mut().graceful_shutdown();
+                            closing = true;
+                        }
+                    }
+                }
             }
         });
     }
+
+    Ok(())
 }
 
-static NEXT_WORKER: LazyLock<Arc<Mutex<usize>>> = LazyLock::new(|| Arc::new(Mutex::new(0)));
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use messages::codec::{HandshakeCodec, WorkerCodec, DEFAULT_MAX_FRAME_SIZE};
+    use messages::types::{
+        Hello, HttpResponseBody, HttpResponseEarlyHint, HttpResponseStart, HttpResponseTrailers,
+    };
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+    use tokio::net::{TcpStream, UnixListener, UnixStream};
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    type Worker = Framed<UnixStream, WorkerCodec>;
+
+    /// Serves one client connection like the server does, with requests going to a worker
+    /// that runs `worker` on its connection. Returns the client's end.
+    async fn serve<F, Fut>(name: &str, worker: F) -> DuplexStream
+    where
+        F: FnOnce(Worker) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        serve_on(name, server, worker).await;
+
+        client
+    }
+
+    /// Like [`serve`], on the server's end `server` of a client connection.
+    async fn serve_on<S, F, Fut>(name: &str, server: S, worker: F)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: FnOnce(Worker) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let sock_file = std::env::temp_dir().join(format!(
+            "ferricorn_test_{}_{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&sock_file);
+        let listener = UnixListener::bind(&sock_file).unwrap();
+        let pool = Arc::new(WorkerPool::new(
+            0,
+            sock_file.to_string_lossy().into_owned(),
+            DEFAULT_MAX_FRAME_SIZE,
+            1,
+        ));
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = std::fs::remove_file(&sock_file);
+
+            let mut framed = Framed::new(stream, HandshakeCodec::new(DEFAULT_MAX_FRAME_SIZE));
+            framed.next().await.unwrap().unwrap();
+            framed
+                .send(Hello::new(upstream::CAPABILITIES))
+                .await
+                .unwrap();
+            worker(framed.map_codec(|_| WorkerCodec::new(DEFAULT_MAX_FRAME_SIZE))).await;
+        });
+
+        let cli = Arc::new(Arguments::parse_from(["asgi"]));
+        let (server, hints) = EarlyHints::wrap(server);
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let pool = Arc::clone(&pool);
+                let cli = Arc::clone(&cli);
+                let hints = hints.clone();
+
+                async move {
+                    hints.open(true);
+                    let fields = req
+                        .headers()
+                        .iter()
+                        .map(|(k, v)| (k.as_str().as_bytes().to_vec(), v.as_bytes().to_vec()))
+                        .collect();
+                    let in_flight = pool.start_request();
+                    let response = process_request(
+                        req,
+                        (pool, in_flight),
+                        &cli,
+                        None,
+                        "test".into(),
+                        fields,
+                        &hints,
+                    )
+                    .await;
+                    hints.close();
+                    response
+                }
+            });
 
-async fn round_robin(workers: &Arc<Mutex<Vec<String>>>) -> String {
-    let mut next_worker_index = NEXT_WORKER.lock().await;
-    let workers = workers.lock().await;
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(server), service)
+                .await;
+        });
+    }
 
-    let next_worker = workers.get(*next_worker_index).unwrap();
+    async fn request(worker: &mut Worker) -> ParsedRequest {
+        match worker.next().await {
+            Some(Ok(WorkerRequest::Http(request))) => *request,
+            other => panic!("Expected a request, got {:?}", other.map(|r| r.is_ok())),
+        }
+    }
 
-    *next_worker_index += 1;
+    async fn respond(worker: &mut Worker, status: u16, body: &[u8]) {
+        let start = HttpResponseStart::new("http.response.start", status);
+        let body = HttpResponseBody::new(body.to_vec(), false);
+        worker
+            .send(ASGIMessages::HttpResponseStart(start))
+            .await
+            .unwrap();
+        worker
+            .send(ASGIMessages::HttpResponseBody(body))
+            .await
+            .unwrap();
+    }
 
-    if *next_worker_index >= workers.len() {
-        *next_worker_index = 0;
+    async fn read_to_end(client: &mut DuplexStream) -> String {
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        response
     }
 
-    next_worker.to_owned()
+    #[tokio::test]
+    async fn answers_without_reading_a_held_back_body() {
+        let mut client = serve("reject", |mut worker| async move {
+            assert!(request(&mut worker).await.body_deferred);
+            respond(&mut worker, 413, b"too big").await;
+        })
+        .await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_to_end(&mut client).await;
+
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(response.ends_with("too big"));
+        assert!(!response.contains("100 Continue"));
+    }
+
+    #[tokio::test]
+    async fn sends_100_continue_once_the_worker_reads_the_body() {
+        let (arrived, has_arrived) = oneshot::channel();
+        let (go, may_go) = oneshot::channel::<()>();
+        let mut client = serve("continue", |mut worker| async move {
+            assert!(request(&mut worker).await.body_deferred);
+            arrived.send(()).unwrap();
+            may_go.await.unwrap();
+
+            worker.send(ASGIMessages::ReadBody).await.unwrap();
+            let body = match worker.next().await {
+                Some(Ok(WorkerRequest::Body(body))) => body,
+                _ => panic!("Expected the request body"),
+            };
+            respond(&mut worker, 200, &body).await;
+        })
+        .await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        has_arrived.await.unwrap();
+
+        // Nothing until the worker asks for the body
+        let mut buf = [0; 64];
+        let early = timeout(Duration::from_millis(100), client.read(&mut buf)).await;
+        assert!(early.is_err());
+
+        go.send(()).unwrap();
+        let continued = b"HTTP/1.1 100 Continue\r\n\r\n";
+        let mut buf = [0; 25];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, continued);
+
+        client.write_all(b"hello").await.unwrap();
+        let response = read_to_end(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_response_started_twice() {
+        let mut client = serve("twice", |mut worker| async move {
+            request(&mut worker).await;
+            let start = HttpResponseStart::new("http.response.start", 200);
+            worker
+                .send(ASGIMessages::HttpResponseStart(start))
+                .await
+                .unwrap();
+            respond(&mut worker, 201, b"again").await;
+        })
+        .await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_to_end(&mut client).await;
+
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    }
+
+    #[tokio::test]
+    async fn sends_trailers_to_clients_that_accept_them() {
+        for te in ["TE: trailers\r\n", ""] {
+            let mut client = serve("trailers", |mut worker| async move {
+                request(&mut worker).await;
+
+                let mut start = HttpResponseStart::new("http.response.start", 200);
+                start.add_header(b"trailer", b"grpc-status");
+                start.add_header(b"content-length", b"5");
+                start.set_trailers(true);
+                let mut trailers = HttpResponseTrailers::new(false);
+                trailers.add_header(b"grpc-status", b"0");
+
+                let messages = [
+                    ASGIMessages::HttpResponseStart(start),
+                    ASGIMessages::HttpResponseBody(HttpResponseBody::new(b"hello".to_vec(), false)),
+                    ASGIMessages::HttpResponseTrailers(trailers),
+                ];
+                for message in messages {
+                    worker.send(message).await.unwrap();
+                }
+            })
+            .await;
+
+            let head = format!(
+                "GET / HTTP/1.1\r\nHost: x\r\n{}Connection: close\r\n\r\n",
+                te
+            );
+            client.write_all(head.as_bytes()).await.unwrap();
+            let response = read_to_end(&mut client).await;
+
+            assert!(response.contains("\r\ntransfer-encoding: chunked\r\n"));
+            assert!(!response.contains("content-length"));
+            match te.is_empty() {
+                false => {
+                    assert!(response.ends_with("\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n"))
+                }
+                true => assert!(response.ends_with("\r\n5\r\nhello\r\n0\r\n\r\n")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_early_hints_while_the_worker_is_still_busy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (hinted, was_hinted) = oneshot::channel::<()>();
+        serve_on("hints", server, |mut worker| async move {
+            request(&mut worker).await;
+            let hint = HttpResponseEarlyHint::new(vec![b"</style.css>; rel=preload".to_vec()]);
+            worker
+                .send(ASGIMessages::HttpResponseEarlyHint(hint))
+                .await
+                .unwrap();
+            // The final response waits for the client to have the hint
+            was_hinted.await.unwrap();
+            respond(&mut worker, 200, b"page").await;
+        })
+        .await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let hint = b"HTTP/1.1 103 Early Hints\r\nlink: </style.css>; rel=preload\r\n\r\n";
+        let mut buf = vec![0; hint.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, hint);
+
+        hinted.send(()).unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("page"));
+    }
 }
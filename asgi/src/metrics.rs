@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{debug, error};
+
+use crate::pool::WorkerPool;
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the request duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters and histograms kept by the front end, rendered in the Prometheus text format
+/// along with what the workers report in their heartbeats.
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    duration_buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    duration_count: AtomicU64,
+    duration_sum_micros: AtomicU64,
+    in_flight: AtomicU64,
+    /// Workers stopped after being drained
+    worker_drains: AtomicU64,
+    /// Workers that exited on their own and were replaced
+    worker_crashes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            duration_buckets: Default::default(),
+            duration_count: AtomicU64::new(0),
+            duration_sum_micros: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            worker_drains: AtomicU64::new(0),
+            worker_crashes: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> InFlightRequest {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightRequest(Arc::clone(self))
+    }
+
+    pub fn observe_request(&self, method: &Method, status: u16, duration: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method_label(method), status))
+            .or_default() += 1;
+
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.duration_buckets.iter().zip(DURATION_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.duration_count.fetch_add(1, Ordering::Relaxed);
+        self.duration_sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a worker exit, `drained` when it was asked to stop.
+    pub fn worker_exited(&self, drained: bool) {
+        match drained {
+            true => &self.worker_drains,
+            false => &self.worker_crashes,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, workers: &[Arc<WorkerPool>]) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "ferricorn_requests_total",
+            "counter",
+            "Requests answered.",
+        );
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "ferricorn_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        header(
+            &mut out,
+            "ferricorn_request_duration_seconds",
+            "histogram",
+            "Time taken to answer requests.",
+        );
+        for (bucket, bound) in self.duration_buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "ferricorn_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.duration_count.load(Ordering::Relaxed);
+        let sum = self.duration_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(
+            out,
+            "ferricorn_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(out, "ferricorn_request_duration_seconds_sum {}", sum);
+        let _ = writeln!(out, "ferricorn_request_duration_seconds_count {}", count);
+
+        header(
+            &mut out,
+            "ferricorn_requests_in_flight",
+            "gauge",
+            "Requests being answered.",
+        );
+        let _ = writeln!(
+            out,
+            "ferricorn_requests_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "ferricorn_workers",
+            "gauge",
+            "Worker processes running.",
+        );
+        let _ = writeln!(out, "ferricorn_workers {}", workers.len());
+
+        header(
+            &mut out,
+            "ferricorn_worker_exits_total",
+            "counter",
+            "Worker processes that exited, after being drained or unexpectedly.",
+        );
+        for (reason, exits) in [
+            ("drained", &self.worker_drains),
+            ("unexpected", &self.worker_crashes),
+        ] {
+            let _ = writeln!(
+                out,
+                "ferricorn_worker_exits_total{{reason=\"{}\"}} {}",
+                reason,
+                exits.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "ferricorn_worker_requests_in_flight",
+            "gauge",
+            "Requests sent to a worker and not answered yet.",
+        );
+        for worker in workers {
+            let _ = writeln!(
+                out,
+                "ferricorn_worker_requests_in_flight{{worker=\"{}\"}} {}",
+                worker.id(),
+                worker.in_flight()
+            );
+        }
+
+        let heartbeats: Vec<_> = workers
+            .iter()
+            .filter_map(|worker| Some((worker.id(), worker.last_heartbeat()?)))
+            .collect();
+
+        header(
+            &mut out,
+            "ferricorn_worker_queue_depth",
+            "gauge",
+            "Requests waiting for the app in a worker, as of its last heartbeat.",
+        );
+        for (id, heartbeat) in &heartbeats {
+            let _ = writeln!(
+                out,
+                "ferricorn_worker_queue_depth{{worker=\"{}\"}} {}",
+                id, heartbeat.queue_depth
+            );
+        }
+
+        header(
+            &mut out,
+            "ferricorn_worker_resident_memory_bytes",
+            "gauge",
+            "Resident memory of a worker, as of its last heartbeat.",
+        );
+        for (id, heartbeat) in &heartbeats {
+            if let Some(rss_bytes) = heartbeat.rss_bytes {
+                let _ = writeln!(
+                    out,
+                    "ferricorn_worker_resident_memory_bytes{{worker=\"{}\",pid=\"{}\"}} {}",
+                    id, heartbeat.pid, rss_bytes
+                );
+            }
+        }
+
+        out
+    }
+}
+
+pub struct InFlightRequest(Arc<Metrics>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves `GET /metrics` on `listener` until the server exits.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    workers: Arc<AsyncMutex<Vec<Arc<WorkerPool>>>>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!(error = %err, "Failed to accept metrics connection");
+                continue;
+            }
+        };
+
+        let metrics = Arc::clone(&metrics);
+        let workers = Arc::clone(&workers);
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let metrics = Arc::clone(&metrics);
+                let workers = Arc::clone(&workers);
+
+                async move {
+                    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+                        let mut response = Response::new(Full::new(Bytes::new()));
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        return Ok::<_, hyper::Error>(response);
+                    }
+
+                    let rendered = metrics.render(&workers.lock().await);
+                    let mut response = Response::new(Full::new(Bytes::from(rendered)));
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+                    Ok(response)
+                }
+            });
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(error = %err, "Error serving metrics connection");
+            }
+        });
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Extension methods are lumped together, clients must not be able to create new series.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "OTHER",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let metrics = Arc::new(Metrics::new());
+        let _in_flight = metrics.start_request();
+        drop(metrics.start_request());
+        metrics.worker_exited(true);
+        metrics.worker_exited(true);
+        metrics.worker_exited(false);
+        metrics.observe_request(&Method::GET, 200, Duration::from_millis(20));
+        metrics.observe_request(&Method::GET, 200, Duration::from_secs(30));
+        metrics.observe_request(
+            &Method::from_bytes(b"PROPFIND").unwrap(),
+            404,
+            Duration::from_millis(1),
+        );
+
+        let rendered = metrics.render(&[]);
+
+        assert!(rendered.contains("ferricorn_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(rendered.contains("ferricorn_requests_total{method=\"OTHER\",status=\"404\"} 1\n"));
+        assert!(rendered.contains("ferricorn_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("ferricorn_request_duration_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(rendered.contains("ferricorn_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(rendered.contains("ferricorn_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("ferricorn_request_duration_seconds_sum 30.021\n"));
+        assert!(rendered.contains("ferricorn_requests_in_flight 1\n"));
+        assert!(rendered.contains("ferricorn_workers 0\n"));
+        assert!(rendered.contains("ferricorn_worker_exits_total{reason=\"drained\"} 2\n"));
+        assert!(rendered.contains("ferricorn_worker_exits_total{reason=\"unexpected\"} 1\n"));
+    }
+}
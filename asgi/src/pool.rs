@@ -0,0 +1,232 @@
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use messages::codec::CodecError;
+use messages::types::{ASGIMessages, Heartbeat, WorkerRequest};
+use tokio::time::timeout;
+
+use crate::upstream::WorkerConnection;
+
+/// Longest a worker gets to answer a heartbeat, a busy app doesn't delay it.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keeps connections to one worker open between requests instead of reconnecting every
+/// time; the worker already serves any number of requests per connection.
+pub struct WorkerPool {
+    id: usize,
+    sock_file: String,
+    max_frame_size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<WorkerConnection>>,
+    in_flight: Arc<AtomicUsize>,
+    heartbeat: Mutex<Option<Heartbeat>>,
+}
+
+impl WorkerPool {
+    pub fn new(id: usize, sock_file: String, max_frame_size: usize, max_idle: usize) -> Self {
+        Self {
+            id,
+            sock_file,
+            max_frame_size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            heartbeat: Mutex::new(None),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn sock_file(&self) -> &str {
+        &self.sock_file
+    }
+
+    /// Requests sent to this worker that haven't been answered yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn start_request(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(Arc::clone(&self.in_flight))
+    }
+
+    /// Like [`WorkerPool::start_request`] unless `max` requests are already in flight, checked
+    /// and counted at once so that concurrent requests can't go over it.
+    pub fn try_start_request(&self, max: Option<usize>) -> Option<InFlight> {
+        let max = max.unwrap_or(usize::MAX);
+        self.in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+
+        Some(InFlight(Arc::clone(&self.in_flight)))
+    }
+
+    /// Takes an idle connection that is still healthy, or opens a new one.
+    pub async fn get(&self) -> Result<WorkerConnection, CodecError> {
+        loop {
+            let Some(connection) = self.idle.lock().unwrap().pop() else {
+                break;
+            };
+
+            if is_healthy(&connection) {
+                return Ok(connection);
+            }
+        }
+
+        WorkerConnection::connect(&self.sock_file, self.max_frame_size).await
+    }
+
+    /// Asks the worker how it's doing, the answer is kept for [`WorkerPool::last_heartbeat`]
+    /// and forgotten when the worker doesn't answer within [`HEARTBEAT_TIMEOUT`].
+    pub async fn heartbeat(&self) -> Result<Heartbeat, CodecError> {
+        let answer = match timeout(HEARTBEAT_TIMEOUT, self.ask_heartbeat()).await {
+            Ok(answer) => answer,
+            Err(_) => Err(io::Error::from(ErrorKind::TimedOut).into()),
+        };
+        *self.heartbeat.lock().unwrap() = answer.as_ref().ok().copied();
+
+        answer
+    }
+
+    async fn ask_heartbeat(&self) -> Result<Heartbeat, CodecError> {
+        let mut connection = self.get().await?;
+        connection.framed.send(WorkerRequest::Heartbeat).await?;
+
+        let heartbeat = match connection.framed.next().await {
+            Some(Ok(ASGIMessages::Heartbeat(heartbeat))) => heartbeat,
+            Some(Ok(_)) => return Err(io::Error::from(ErrorKind::InvalidData).into()),
+            Some(Err(err)) => return Err(err),
+            None => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+        };
+
+        self.put(connection);
+
+        Ok(heartbeat)
+    }
+
+    /// The worker's answer to the latest heartbeat, `None` if it didn't answer.
+    pub fn last_heartbeat(&self) -> Option<Heartbeat> {
+        *self.heartbeat.lock().unwrap()
+    }
+
+    /// Hands a connection back once its response has been read completely; connections
+    /// dropped instead are closed.
+    pub fn put(&self, connection: WorkerConnection) {
+        let mut idle = self.idle.lock().unwrap();
+
+        if idle.len() < self.max_idle {
+            idle.push(connection);
+        }
+    }
+}
+
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An idle connection has nothing to read, anything else means the worker closed it (e.g.
+/// it was restarted) or it's out of sync.
+fn is_healthy(connection: &WorkerConnection) -> bool {
+    if !connection.framed.read_buffer().is_empty() {
+        return false;
+    }
+
+    let mut buf = [0u8; 1];
+
+    matches!(
+        connection.framed.get_ref().try_read(&mut buf),
+        Err(err) if err.kind() == ErrorKind::WouldBlock
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use messages::codec::{ClientCodec, DEFAULT_MAX_FRAME_SIZE};
+    use messages::types::Capabilities;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    fn connection() -> (WorkerConnection, UnixStream) {
+        let (stream, worker) = UnixStream::pair().unwrap();
+        let connection = WorkerConnection {
+            framed: Framed::new(stream, ClientCodec::new(DEFAULT_MAX_FRAME_SIZE)),
+            capabilities: Capabilities::empty(),
+        };
+
+        (connection, worker)
+    }
+
+    /// Lets the runtime notice what happened on the sockets.
+    async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn tells_idle_connections_from_closed_or_out_of_sync_ones() {
+        let (idle, _worker) = connection();
+        assert!(is_healthy(&idle));
+
+        let (closed, worker) = connection();
+        drop(worker);
+        settle().await;
+        assert!(!is_healthy(&closed));
+
+        let (unread, mut worker) = connection();
+        worker.write_all(b"x").await.unwrap();
+        settle().await;
+        assert!(!is_healthy(&unread));
+    }
+
+    #[tokio::test]
+    async fn keeps_up_to_max_idle_connections() {
+        let pool = WorkerPool::new(
+            0,
+            "/nonexistent.sock".to_string(),
+            DEFAULT_MAX_FRAME_SIZE,
+            1,
+        );
+        let (first, _first_worker) = connection();
+        let (second, _second_worker) = connection();
+
+        pool.put(first);
+        pool.put(second);
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        // Reuses the idle connection, then has to connect to a socket that isn't there
+        assert!(pool.get().await.is_ok());
+        assert!(pool.get().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_idle_connections_the_worker_closed() {
+        let pool = WorkerPool::new(
+            0,
+            "/nonexistent.sock".to_string(),
+            DEFAULT_MAX_FRAME_SIZE,
+            2,
+        );
+        let (closed, worker) = connection();
+        pool.put(closed);
+        drop(worker);
+        settle().await;
+
+        assert!(pool.get().await.is_err());
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+}
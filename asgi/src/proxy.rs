@@ -0,0 +1,180 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use hyper::HeaderMap;
+
+use crate::args::Arguments;
+
+const FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// A `--forwarded-allow-ips` entry: "*", an address, or a network like `10.0.0.0/8`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AllowedIps {
+    Any,
+    /// An address and the number of leading bits a peer's must share with it
+    Network(IpAddr, u8),
+}
+
+impl AllowedIps {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let AllowedIps::Network(network, prefix) = *self else {
+            return true;
+        };
+
+        // Shifting out every bit is `None`, a zero prefix matches everything
+        match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) ^ u32::from(ip))
+                .checked_shr(32 - u32::from(prefix))
+                .is_none_or(|diff| diff == 0),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network) ^ u128::from(ip))
+                .checked_shr(128 - u32::from(prefix))
+                .is_none_or(|diff| diff == 0),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AllowedIps {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value == "*" {
+            return Ok(AllowedIps::Any);
+        }
+
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("{}: {}", err, value))?;
+
+        match prefix {
+            Some(prefix) => {
+                let bits = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|&prefix| prefix <= bits)
+                    .ok_or_else(|| format!("invalid network prefix: {}", value))?;
+
+                Ok(AllowedIps::Network(addr, prefix))
+            }
+            // Written IPv4-mapped, peers are compared in their IPv4 form
+            None => match addr.to_canonical() {
+                addr @ IpAddr::V4(_) => Ok(AllowedIps::Network(addr, 32)),
+                addr @ IpAddr::V6(_) => Ok(AllowedIps::Network(addr, 128)),
+            },
+        }
+    }
+}
+
+/// Whether the peer is one of the `--forwarded-allow-ips` proxies. Connections coming in
+/// through a Unix socket are always trusted, only a local proxy can reach them.
+pub fn is_trusted(cli: &Arguments, peer: Option<SocketAddr>) -> bool {
+    let Some(peer) = peer else {
+        return true;
+    };
+
+    // A dual-stack listener sees IPv4 clients as ::ffff:a.b.c.d
+    let ip = peer.ip().to_canonical();
+    cli.forwarded_allow_ips
+        .iter()
+        .any(|allowed| allowed.contains(ip))
+}
+
+/// The ASGI `root_path` for a request: `X-Forwarded-Prefix` when sent by a trusted proxy,
+/// `--root-path` otherwise.
+pub fn root_path(cli: &Arguments, peer: Option<SocketAddr>, headers: &HeaderMap) -> String {
+    let forwarded = headers
+        .get(FORWARDED_PREFIX)
+        .and_then(|prefix| prefix.to_str().ok())
+        .filter(|_| is_trusted(cli, peer));
+
+    let root_path = forwarded.unwrap_or(&cli.root_path).trim_end_matches('/');
+
+    if root_path.is_empty() || root_path.starts_with('/') {
+        root_path.to_string()
+    } else {
+        format!("/{}", root_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn prefixed(prefix: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_PREFIX, HeaderValue::from_str(prefix).unwrap());
+        headers
+    }
+
+    #[test]
+    fn takes_the_prefix_from_trusted_proxies_only() {
+        let cli = Arguments::parse_from(["asgi", "--root-path", "/app/"]);
+        let local = Some(SocketAddr::from(([127, 0, 0, 1], 5000)));
+        let remote = Some(SocketAddr::from(([192, 0, 2, 1], 5000)));
+
+        assert_eq!(root_path(&cli, local, &prefixed("/proxied/")), "/proxied");
+        assert_eq!(root_path(&cli, remote, &prefixed("/proxied")), "/app");
+        assert_eq!(root_path(&cli, local, &HeaderMap::new()), "/app");
+        assert_eq!(root_path(&cli, local, &prefixed("relative")), "/relative");
+        assert_eq!(root_path(&cli, local, &prefixed("/")), "");
+    }
+
+    #[test]
+    fn always_trusts_unix_socket_peers() {
+        let cli = Arguments::parse_from(["asgi", "--forwarded-allow-ips", "192.0.2.1"]);
+
+        assert!(is_trusted(&cli, None));
+        assert_eq!(root_path(&cli, None, &prefixed("/proxied")), "/proxied");
+        assert!(is_trusted(
+            &cli,
+            Some(SocketAddr::from(([192, 0, 2, 1], 80)))
+        ));
+        assert!(!is_trusted(
+            &cli,
+            Some(SocketAddr::from(([127, 0, 0, 1], 80)))
+        ));
+
+        let cli = Arguments::parse_from(["asgi", "--forwarded-allow-ips", "*"]);
+        assert!(is_trusted(
+            &cli,
+            Some(SocketAddr::from(([192, 0, 2, 9], 80)))
+        ));
+    }
+
+    #[test]
+    fn trusts_networks_and_mapped_addresses() {
+        let cli = Arguments::parse_from([
+            "asgi",
+            "--forwarded-allow-ips",
+            "10.1.0.0/16, ::ffff:127.0.0.1,2001:db8::/32",
+        ]);
+        let trusted = |ip: &str| is_trusted(&cli, Some(SocketAddr::new(ip.parse().unwrap(), 80)));
+
+        assert!(trusted("10.1.200.3"));
+        assert!(!trusted("10.2.0.1"));
+        assert!(trusted("127.0.0.1"));
+        assert!(trusted("::ffff:10.1.0.1"));
+        assert!(trusted("2001:db8:1::1"));
+        assert!(!trusted("2001:db9::1"));
+        assert!(!trusted("::1"));
+
+        let cli = Arguments::parse_from(["asgi", "--forwarded-allow-ips", "0.0.0.0/0"]);
+        assert!(is_trusted(
+            &cli,
+            Some(SocketAddr::from(([192, 0, 2, 9], 80)))
+        ));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "localhost"] {
+            assert!(invalid.parse::<AllowedIps>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use std::io::{self, ErrorKind, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::StreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, ALLOW, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED,
+    RANGE, VARY,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+use crate::body::{self, ResponseBody, FILE_CHUNK_SIZE};
+
+/// Precompressed siblings looked for next to a file, in order of preference.
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// A directory served under a URL prefix, given as `PREFIX=DIR` on the command line.
+#[derive(Debug, Clone)]
+pub struct Mount {
+    prefix: String,
+    root: PathBuf,
+}
+
+impl Mount {
+    pub fn parse(value: &str) -> Result<Mount, String> {
+        let (prefix, root) = value
+            .split_once('=')
+            .ok_or_else(|| format!("Expected PREFIX=DIR, got {}", value))?;
+
+        if !prefix.starts_with('/') {
+            return Err(format!("Mount prefix {} must start with /", prefix));
+        }
+
+        Ok(Mount {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: PathBuf::from(root),
+        })
+    }
+
+    /// The decoded path below the mount, `None` if `path` isn't under it.
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&self.prefix)?;
+
+        match rest.is_empty() || rest.starts_with('/') {
+            true => Some(rest),
+            false => None,
+        }
+    }
+}
+
+/// Files served by the front end before requests are routed to a worker.
+pub struct StaticFiles {
+    mounts: Vec<Mount>,
+}
+
+impl StaticFiles {
+    pub fn new(mut mounts: Vec<Mount>) -> Self {
+        // The most specific prefix wins
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+
+        Self { mounts }
+    }
+
+    /// Answers requests under one of the mounts, `None` leaves the request to the app.
+    pub async fn serve<B>(&self, req: &Request<B>) -> Option<Response<ResponseBody>> {
+        let path = req.uri().path();
+        let (mount, relative) = self
+            .mounts
+            .iter()
+            .find_map(|mount| Some((mount, mount.relative(path)?)))?;
+
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut response = empty(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Some(response);
+        }
+
+        let Ok(root) = fs::canonicalize(&mount.root).await else {
+            return Some(empty(StatusCode::NOT_FOUND));
+        };
+        let Some(file) = resolve(&root, relative).await else {
+            return Some(empty(StatusCode::NOT_FOUND));
+        };
+
+        match respond(req, &root, &file).await {
+            Ok(response) => Some(response),
+            Err(err) if err.kind() == ErrorKind::NotFound => Some(empty(StatusCode::NOT_FOUND)),
+            Err(err) => {
+                warn!(path = %file.display(), error = %err, "Failed to serve static file");
+                Some(empty(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
+    }
+}
+
+/// Maps the URL path below a mount to a file inside its canonical root. Anything that could
+/// lead outside of it, `..` segments or symlinks pointing elsewhere, is refused.
+async fn resolve(root: &Path, relative: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(relative).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            _ if segment.contains('\0') => return None,
+            _ => (),
+        }
+
+        // A segment like "C:" or one with a separator can't add more than one component
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => return None,
+        }
+    }
+
+    contained(root, &path).await
+}
+
+/// The canonical form of `path` if it's a file inside `root`, once symlinks are followed.
+async fn contained(root: &Path, path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).await.ok()?;
+
+    match path.starts_with(root) && fs::metadata(&path).await.ok()?.is_file() {
+        true => Some(path),
+        false => None,
+    }
+}
+
+async fn respond<B>(
+    req: &Request<B>,
+    root: &Path,
+    path: &Path,
+) -> io::Result<Response<ResponseBody>> {
+    let (served, encoding) = match precompressed(req.headers(), root, path).await {
+        Some((sibling, encoding)) => (sibling, Some(encoding)),
+        None => (path.to_path_buf(), None),
+    };
+
+    let mut file = File::open(&served).await?;
+    let metadata = file.metadata().await?;
+    let length = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag(length, modified);
+
+    let mut response = empty(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(path)));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+    headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    if let Some(modified) = modified {
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
+        );
+    }
+    if let Some(encoding) = encoding {
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+
+    if not_modified(req.headers(), &etag, modified) {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(response);
+    }
+
+    let range = match req.headers().get(RANGE) {
+        Some(range) if if_range_matches(req.headers(), &etag, modified) => {
+            match parse_range(range.as_bytes(), length) {
+                Some(Ok(range)) => Some(range),
+                Some(Err(())) => {
+                    *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                    response.headers_mut().insert(
+                        CONTENT_RANGE,
+                        HeaderValue::from_str(&format!("bytes */{}", length)).unwrap(),
+                    );
+                    return Ok(response);
+                }
+                None => None,
+            }
+        }
+        _ => None,
+    };
+
+    let (start, end) = range.unwrap_or((0, length.saturating_sub(1)));
+    let size = match length {
+        0 => 0,
+        _ => end - start + 1,
+    };
+
+    if let Some((start, end)) = range {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, length)).unwrap(),
+        );
+    }
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from(size));
+
+    if req.method() == Method::GET {
+        file.seek(SeekFrom::Start(start)).await?;
+        let frames = ReaderStream::with_capacity(file.take(size), FILE_CHUNK_SIZE)
+            .map(|chunk| chunk.map(Frame::data));
+        *response.body_mut() = BodyExt::boxed(StreamBody::new(frames));
+    }
+
+    Ok(response)
+}
+
+/// A `.br` or `.gz` file next to `path` the client accepts, with its content coding. Like
+/// `path` itself, it has to be inside `root`.
+async fn precompressed(
+    headers: &HeaderMap,
+    root: &Path,
+    path: &Path,
+) -> Option<(PathBuf, &'static str)> {
+    let accepted = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+
+    for (encoding, extension) in PRECOMPRESSED {
+        if !accepts(accepted, encoding) {
+            continue;
+        }
+
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+
+        if let Some(sibling) = contained(root, Path::new(&sibling)).await {
+            return Some((sibling, encoding));
+        }
+    }
+
+    None
+}
+
+/// Whether an Accept-Encoding value allows `encoding`, `q=0` ruling it out.
+fn accepts(accepted: &str, encoding: &str) -> bool {
+    accepted.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+
+        name.eq_ignore_ascii_case(encoding) && !refused
+    })
+}
+
+fn etag(length: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_nanos());
+
+    format!("\"{:x}-{:x}\"", modified, length)
+}
+
+/// Whether the client's copy is current, If-None-Match taking precedence over
+/// If-Modified-Since.
+fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|tags| {
+            tags.split(',').map(str::trim).any(|tag| {
+                // Weak comparison, W/ makes no difference for a 304
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            })
+        });
+    }
+
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|since| httpdate::parse_http_date(since.to_str().ok()?).ok());
+
+    match (since, modified) {
+        // HTTP dates have a resolution of a second
+        (Some(since), Some(modified)) => modified
+            .duration_since(since)
+            .map_or(true, |newer| newer.as_secs() == 0),
+        _ => false,
+    }
+}
+
+/// A Range is only honoured when the If-Range validator, if any, is still current.
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = headers.get(IF_RANGE) else {
+        return true;
+    };
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
+    };
+
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+
+    match (httpdate::parse_http_date(if_range), modified) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date)
+        }
+        _ => false,
+    }
+}
+
+/// Parses a single `bytes=` range into inclusive offsets. `None` means the header is
+/// ignored (other units, several ranges, garbage), `Some(Err)` that it can't be satisfied.
+fn parse_range(range: &[u8], length: u64) -> Option<Result<(u64, u64), ()>> {
+    let range = std::str::from_utf8(range)
+        .ok()?
+        .strip_prefix("bytes=")?
+        .trim();
+    if range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 || length == 0 {
+                return Some(Err(()));
+            }
+            (length.saturating_sub(suffix), length - 1)
+        }
+        (start, "") => (start.parse().ok()?, length.saturating_sub(1)),
+        (start, end) => {
+            let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(length.saturating_sub(1)))
+        }
+    };
+
+    match start < length {
+        true => Some(Ok((start, end))),
+        false => Some(Err(())),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "webmanifest" => "application/manifest+json",
+        _ => "application/octet-stream",
+    }
+}
+
+fn empty(status: StatusCode) -> Response<ResponseBody> {
+    let mut response = Response::new(body::full(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range(b"bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(parse_range(b"bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range(b"bytes=-10", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range(b"bytes=50-500", 100), Some(Ok((50, 99))));
+        assert_eq!(parse_range(b"bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range(b"bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range(b"items=0-1", 100), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_outside_the_root() {
+        let root = std::env::temp_dir().join(format!("ferricorn_static_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::write(root.join("css/site.css"), "body {}").unwrap();
+        let root = fs::canonicalize(&root).await.unwrap();
+
+        assert!(resolve(&root, "/css/site.css").await.is_some());
+        assert!(resolve(&root, "/css/%2e%2e/css/site.css").await.is_none());
+        assert!(resolve(&root, "/../etc/passwd").await.is_none());
+        assert!(resolve(&root, "/%2e%2e%2fetc/passwd").await.is_none());
+        assert!(resolve(&root, "/css").await.is_none());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_precompressed_siblings_outside_the_root() {
+        let dir = std::env::temp_dir().join(format!("ferricorn_siblings_{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.js"), "a").unwrap();
+        std::fs::write(root.join("a.js.gz"), "gz").unwrap();
+        std::fs::write(root.join("b.js"), "b").unwrap();
+        std::fs::write(dir.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("b.js.gz")).unwrap();
+
+        let root = fs::canonicalize(&root).await.unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+
+        assert_eq!(
+            precompressed(&headers, &root, &root.join("a.js")).await,
+            Some((root.join("a.js.gz"), "gzip"))
+        );
+        assert_eq!(
+            precompressed(&headers, &root, &root.join("b.js")).await,
+            None
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_the_requested_range() {
+        let root = std::env::temp_dir().join(format!("ferricorn_range_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("data.txt"), "0123456789").unwrap();
+        let root = fs::canonicalize(&root).await.unwrap();
+
+        let req = Request::builder()
+            .header(RANGE, "bytes=2-5")
+            .body(())
+            .unwrap();
+        let response = respond(&req, &root, &root.join("data.txt")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "4");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "2345");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,653 @@
+use std::collections::BTreeMap;
+use std::io::{self, stderr, stdout};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::args::Arguments;
+use crate::metrics::Metrics;
+use crate::pool::WorkerPool;
+
+const SOCK_FILE: &str = "/tmp/ferricorn_worker";
+/// How often a starting worker is asked whether it's up, and a draining one whether it's idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest a draining worker is given to finish its requests before it's stopped anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often running workers are asked for a heartbeat, see [`poll_heartbeats`].
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before replacing a worker that exited on its own, doubled for each one in a row
+/// that exits before its app started so that an app failing at startup isn't restarted in
+/// a busy loop.
+const RESPAWN_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait before replacing a worker.
+const MAX_RESPAWN_DELAY: Duration = Duration::from_secs(60);
+
+/// Workers per CPU allowed when autoscaling doesn't set a maximum.
+const MAX_WORKERS_PER_CPU: usize = 4;
+
+/// Builds the command starting worker `id` on its socket.
+type WorkerCommand = fn(cli: &Arguments, sock_file: &str, id: usize) -> Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Spawned, not answering yet
+    Starting,
+    /// Receiving requests
+    Running,
+    /// Finishing its requests before it's stopped, gets no new ones
+    Draining,
+}
+
+struct Process {
+    pool: Arc<WorkerPool>,
+    pid: Option<u32>,
+    state: State,
+}
+
+/// What the admin API shows of a worker.
+#[derive(Debug, Serialize)]
+pub struct WorkerInfo {
+    pub id: usize,
+    pub pid: Option<u32>,
+    pub sock: String,
+    pub state: State,
+    pub in_flight: usize,
+    pub queue_depth: Option<u64>,
+    pub rss_bytes: Option<u64>,
+}
+
+/// Starts and stops the worker processes, keeping the list requests are balanced over to
+/// the ones that are running.
+pub struct Supervisor {
+    cli: Arc<Arguments>,
+    metrics: Arc<Metrics>,
+    workers: Arc<Mutex<Vec<Arc<WorkerPool>>>>,
+    processes: StdMutex<BTreeMap<usize, Process>>,
+    next_id: AtomicUsize,
+    /// Workers replaced since one last started, see [`RESPAWN_DELAY`]
+    respawns: AtomicU32,
+    bounds: RangeInclusive<usize>,
+    sock_prefix: String,
+    command: WorkerCommand,
+}
+
+impl Supervisor {
+    /// The number of workers is kept within `bounds`, however it's changed.
+    pub fn new(
+        cli: Arc<Arguments>,
+        metrics: Arc<Metrics>,
+        bounds: RangeInclusive<usize>,
+    ) -> Arc<Self> {
+        Self::with_command(cli, metrics, bounds, SOCK_FILE.to_string(), cargo_run)
+    }
+
+    fn with_command(
+        cli: Arc<Arguments>,
+        metrics: Arc<Metrics>,
+        bounds: RangeInclusive<usize>,
+        sock_prefix: String,
+        command: WorkerCommand,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            cli,
+            metrics,
+            workers: Arc::new(Mutex::new(Vec::new())),
+            processes: StdMutex::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(0),
+            respawns: AtomicU32::new(0),
+            bounds,
+            sock_prefix,
+            command,
+        })
+    }
+
+    /// The running workers, the ones requests can be sent to.
+    pub fn workers(&self) -> Arc<Mutex<Vec<Arc<WorkerPool>>>> {
+        Arc::clone(&self.workers)
+    }
+
+    pub fn list(&self) -> Vec<WorkerInfo> {
+        self.processes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, process)| {
+                let heartbeat = process.pool.last_heartbeat();
+
+                WorkerInfo {
+                    id,
+                    pid: process.pid,
+                    sock: process.pool.sock_file().to_string(),
+                    state: process.state,
+                    in_flight: process.pool.in_flight(),
+                    queue_depth: heartbeat.map(|heartbeat| heartbeat.queue_depth),
+                    rss_bytes: heartbeat.and_then(|heartbeat| heartbeat.rss_bytes),
+                }
+            })
+            .collect()
+    }
+
+    /// Starts a worker, it gets requests once it answers a heartbeat.
+    pub fn spawn(self: &Arc<Self>) -> io::Result<usize> {
+        let cli = &self.cli;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let sock_file = format!("{}_{}", self.sock_prefix, id);
+
+        let mut child = (self.command)(cli, &sock_file, id)
+            .stdout(stdout())
+            .stderr(stderr())
+            .spawn()?;
+
+        let pid = child.id();
+        let pool = Arc::new(WorkerPool::new(
+            id,
+            sock_file,
+            cli.max_frame_size,
+            cli.worker_connections,
+        ));
+        info!(worker = id, pid, "Started worker");
+
+        self.processes.lock().unwrap().insert(
+            id,
+            Process {
+                pool: Arc::clone(&pool),
+                pid,
+                state: State::Starting,
+            },
+        );
+
+        let supervisor = Arc::clone(self);
+        tokio::spawn(async move {
+            let exited = tokio::select! {
+                exited = child.wait() => Some(exited),
+                _ = wait_until_up(&pool) => None,
+            };
+
+            let exited = match exited {
+                Some(exited) => exited,
+                None => {
+                    supervisor.set_running(id, &pool).await;
+                    child.wait().await
+                }
+            };
+
+            supervisor.exited(id, pid, exited).await;
+        });
+
+        Ok(id)
+    }
+
+    /// Stops sending requests to worker `id` and stops it once those it has are answered.
+    pub async fn drain(self: &Arc<Self>, id: usize) -> Result<(), String> {
+        let (pool, pid) = {
+            let mut processes = self.processes.lock().unwrap();
+            let process = processes
+                .get_mut(&id)
+                .ok_or_else(|| format!("No worker {}", id))?;
+
+            if process.state == State::Draining {
+                return Ok(());
+            }
+            process.state = State::Draining;
+
+            (Arc::clone(&process.pool), process.pid)
+        };
+
+        self.workers
+            .lock()
+            .await
+            .retain(|worker| !Arc::ptr_eq(worker, &pool));
+        info!(worker = id, "Draining worker");
+
+        tokio::spawn(async move {
+            let deadline = Instant::now() + DRAIN_TIMEOUT;
+
+            loop {
+                sleep(POLL_INTERVAL).await;
+
+                if pool.in_flight() == 0 {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    warn!(
+                        worker = id,
+                        in_flight = pool.in_flight(),
+                        "Stopping worker before it finished its requests"
+                    );
+                    break;
+                }
+            }
+
+            if let Some(pid) = pid {
+                terminate(pid);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Starts or drains workers until `count` of them aren't draining, the newest are drained
+    /// first.
+    pub async fn scale(self: &Arc<Self>, count: usize) -> Result<(), String> {
+        if !self.bounds.contains(&count) {
+            return Err(format!(
+                "Between {} and {} workers are allowed",
+                self.bounds.start(),
+                self.bounds.end()
+            ));
+        }
+
+        let active = self.active();
+
+        for _ in active.len()..count {
+            self.spawn()
+                .map_err(|err| format!("Failed to start a worker: {}", err))?;
+        }
+
+        for &id in active.iter().skip(count) {
+            self.drain(id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts one more worker.
+    pub async fn add(self: &Arc<Self>) -> Result<(), String> {
+        self.scale(self.count() + 1).await
+    }
+
+    /// Drains the newest worker.
+    pub async fn remove(self: &Arc<Self>) -> Result<(), String> {
+        self.scale(self.count().saturating_sub(1)).await
+    }
+
+    /// Workers that aren't draining, whether they're up yet or not.
+    pub fn count(&self) -> usize {
+        self.active().len()
+    }
+
+    /// Replaces every worker with a new one, each old worker is drained once its replacement
+    /// is running so capacity never drops.
+    pub fn reload(self: &Arc<Self>) -> Result<(), String> {
+        let mut replacements = Vec::new();
+
+        for old in self.active() {
+            let new = self
+                .spawn()
+                .map_err(|err| format!("Failed to start a worker: {}", err))?;
+            replacements.push((old, new));
+        }
+
+        let supervisor = Arc::clone(self);
+        tokio::spawn(async move {
+            for (old, new) in replacements {
+                if supervisor.wait_running(new).await {
+                    let _ = supervisor.drain(old).await;
+                } else {
+                    warn!(worker = old, "Keeping worker, its replacement didn't start");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Workers that aren't draining, oldest first.
+    fn active(&self) -> Vec<usize> {
+        self.processes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, process)| process.state != State::Draining)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Whether worker `id` made it to running, false if it exited first.
+    async fn wait_running(&self, id: usize) -> bool {
+        loop {
+            match self.processes.lock().unwrap().get(&id) {
+                Some(process) if process.state == State::Starting => (),
+                Some(_) => return true,
+                None => return false,
+            }
+
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn set_running(&self, id: usize, pool: &Arc<WorkerPool>) {
+        // Holding the list while checking the state: a drain or exit that comes first leaves
+        // the worker out of it, one that comes after waits for it to be in the list to take
+        // it out
+        let mut workers = self.workers.lock().await;
+        {
+            let mut processes = self.processes.lock().unwrap();
+            match processes.get_mut(&id) {
+                Some(process) if process.state == State::Starting => process.state = State::Running,
+                _ => return,
+            }
+        }
+
+        workers.push(Arc::clone(pool));
+        self.respawns.store(0, Ordering::Relaxed);
+        info!(worker = id, "Worker is up");
+    }
+
+    /// Forgets worker `id` and replaces it, unless it was being drained.
+    async fn exited(
+        self: &Arc<Self>,
+        id: usize,
+        pid: Option<u32>,
+        status: io::Result<std::process::ExitStatus>,
+    ) {
+        let process = self.processes.lock().unwrap().remove(&id);
+        self.workers.lock().await.retain(|worker| worker.id() != id);
+
+        let status = status.map_or_else(|err| err.to_string(), |status| status.to_string());
+        let drained = process.is_some_and(|process| process.state == State::Draining);
+        self.metrics.worker_exited(drained);
+
+        if drained {
+            info!(worker = id, pid, status, "Worker stopped");
+            return;
+        }
+        let delay = respawn_delay(self.respawns.fetch_add(1, Ordering::Relaxed));
+        warn!(
+            worker = id,
+            pid,
+            status,
+            ?delay,
+            "Worker exited, replacing it"
+        );
+
+        sleep(delay).await;
+        if let Err(err) = self.spawn() {
+            error!(worker = id, error = %err, "Failed to replace worker");
+        }
+    }
+}
+
+/// How long to wait before replacing a worker after `respawns` were replaced without one
+/// starting.
+fn respawn_delay(respawns: u32) -> Duration {
+    RESPAWN_DELAY
+        .saturating_mul(2u32.saturating_pow(respawns))
+        .min(MAX_RESPAWN_DELAY)
+}
+
+/// Most workers there can be without `--autoscale-max`.
+pub fn default_max_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get()) * MAX_WORKERS_PER_CPU
+}
+
+/// Runs the worker binary of this workspace, built first if needed.
+fn cargo_run(cli: &Arguments, sock_file: &str, id: usize) -> Command {
+    let mut command = Command::new("cargo");
+    command
+        .args([
+            "run",
+            "-p",
+            "worker",
+            "--",
+            "--module",
+            &cli.module,
+            "--sock",
+            sock_file,
+            "--max-frame-size",
+            &cli.max_frame_size.to_string(),
+            "--queue-size",
+            &cli.worker_queue_size.to_string(),
+            "--index",
+            &id.to_string(),
+            "--log-level",
+            &cli.log_level.to_string(),
+            "--log-format",
+            &cli.log_format.to_string(),
+        ])
+        .args(
+            cli.otlp_endpoint
+                .iter()
+                .flat_map(|endpoint| ["--otlp-endpoint", endpoint.as_str()]),
+        );
+
+    command
+}
+
+/// Keeps the running workers' heartbeats fresh until the server exits, for the metrics, the
+/// autoscaler and the readiness probe to read with [`WorkerPool::last_heartbeat`].
+pub async fn poll_heartbeats(workers: Arc<Mutex<Vec<Arc<WorkerPool>>>>) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Not holding the lock while waiting on the workers, nor waiting on one at a time
+        let snapshot = workers.lock().await.clone();
+        join_all(snapshot.iter().map(|worker| async move {
+            if let Err(err) = worker.heartbeat().await {
+                debug!(worker = worker.id(), error = %err, "Worker heartbeat failed");
+            }
+        }))
+        .await;
+    }
+}
+
+/// Waits until the worker answers and its app finished the lifespan startup.
+async fn wait_until_up(pool: &WorkerPool) {
+    loop {
+        match pool.heartbeat().await {
+            Ok(heartbeat) if heartbeat.started => return,
+            Ok(_) => debug!(worker = pool.id(), "Worker still starting the app"),
+            Err(err) => debug!(worker = pool.id(), error = %err, "Worker not up yet"),
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn terminate(pid: u32) {
+    // The worker removes its socket and exits on SIGTERM
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        debug!(pid, error = %io::Error::last_os_error(), "Failed to signal worker");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use futures_util::{SinkExt, StreamExt};
+    use messages::codec::{HandshakeCodec, WorkerCodec, DEFAULT_MAX_FRAME_SIZE};
+    use messages::types::{ASGIMessages, Capabilities, Heartbeat, Hello, WorkerRequest};
+    use tokio::net::UnixListener;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    /// Stands in for the worker process, the worker itself is served by [`fake_worker`].
+    fn sleep_command(_cli: &Arguments, _sock_file: &str, _id: usize) -> Command {
+        let mut command = Command::new("sleep");
+        command.arg("30").kill_on_drop(true);
+        command
+    }
+
+    fn supervisor(name: &str, bounds: RangeInclusive<usize>) -> Arc<Supervisor> {
+        let prefix = format!("/tmp/ferricorn_test_{}_{}", std::process::id(), name);
+
+        Supervisor::with_command(
+            Arc::new(Arguments::parse_from(["asgi"])),
+            Arc::new(Metrics::new()),
+            bounds,
+            prefix,
+            sleep_command,
+        )
+    }
+
+    /// Answers the handshake and heartbeats on worker `id`'s socket, as a worker whose app
+    /// started.
+    fn fake_worker(supervisor: &Supervisor, id: usize) {
+        let sock_file = format!("{}_{}", supervisor.sock_prefix, id);
+        let _ = std::fs::remove_file(&sock_file);
+        let listener = UnixListener::bind(&sock_file).unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut framed =
+                        Framed::new(stream, HandshakeCodec::new(DEFAULT_MAX_FRAME_SIZE));
+                    let Some(Ok(_)) = framed.next().await else {
+                        return;
+                    };
+                    framed
+                        .send(Hello::new(Capabilities::empty()))
+                        .await
+                        .unwrap();
+
+                    let mut framed = framed.map_codec(|_| WorkerCodec::new(DEFAULT_MAX_FRAME_SIZE));
+                    while let Some(Ok(WorkerRequest::Heartbeat)) = framed.next().await {
+                        let heartbeat = Heartbeat {
+                            pid: 1,
+                            queue_depth: 0,
+                            rss_bytes: None,
+                            started: true,
+                        };
+                        if framed
+                            .send(ASGIMessages::Heartbeat(heartbeat))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    fn remove_sockets(supervisor: &Supervisor) {
+        for id in 0..supervisor.next_id.load(Ordering::Relaxed) {
+            let _ = std::fs::remove_file(format!("{}_{}", supervisor.sock_prefix, id));
+        }
+    }
+
+    fn states(supervisor: &Supervisor) -> Vec<(usize, State)> {
+        supervisor
+            .list()
+            .iter()
+            .map(|worker| (worker.id, worker.state))
+            .collect()
+    }
+
+    async fn running(supervisor: &Supervisor) -> Vec<usize> {
+        let workers = supervisor.workers.lock().await;
+        workers.iter().map(|worker| worker.id()).collect()
+    }
+
+    /// Waits for `done` to hold, failing the test after a few seconds.
+    async fn until(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn keeps_workers_drained_while_starting_out_of_the_list() {
+        let supervisor = supervisor("drain", 0..=4);
+        let id = supervisor.spawn().unwrap();
+        assert_eq!(states(&supervisor), [(id, State::Starting)]);
+
+        supervisor.drain(id).await.unwrap();
+        assert_eq!(states(&supervisor), [(id, State::Draining)]);
+        // Comes up after it was drained
+        fake_worker(&supervisor, id);
+
+        until(|| supervisor.list().is_empty()).await;
+        assert!(running(&supervisor).await.is_empty());
+        // Drained workers aren't replaced
+        sleep(RESPAWN_DELAY + POLL_INTERVAL).await;
+        assert!(supervisor.list().is_empty());
+        assert!(supervisor.drain(id).await.is_err());
+        remove_sockets(&supervisor);
+    }
+
+    #[tokio::test]
+    async fn replaces_workers_that_exit() {
+        let supervisor = supervisor("respawn", 0..=4);
+        fake_worker(&supervisor, 0);
+        fake_worker(&supervisor, 1);
+
+        supervisor.spawn().unwrap();
+        supervisor.wait_running(0).await;
+        assert_eq!(running(&supervisor).await, [0]);
+
+        let pid = supervisor.list()[0].pid.unwrap();
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+
+        // Waiting to replace it
+        until(|| supervisor.list().is_empty()).await;
+        assert_eq!(supervisor.respawns.load(Ordering::Relaxed), 1);
+        until(|| supervisor.list().iter().map(|worker| worker.id).eq([1])).await;
+        assert!(supervisor.wait_running(1).await);
+        assert_eq!(running(&supervisor).await, [1]);
+        // Back to the shortest wait once a worker started
+        assert_eq!(supervisor.respawns.load(Ordering::Relaxed), 0);
+
+        supervisor.scale(0).await.unwrap();
+        remove_sockets(&supervisor);
+    }
+
+    #[test]
+    fn backs_off_respawning_up_to_a_limit() {
+        let delays: Vec<_> = [0, 1, 2, 5, 6, 40].map(respawn_delay).into();
+
+        assert_eq!(
+            delays,
+            [1, 2, 4, 32, 60, 60].map(Duration::from_secs).to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn scales_and_reloads_within_bounds() {
+        let supervisor = supervisor("scale", 0..=3);
+        for id in 0..4 {
+            fake_worker(&supervisor, id);
+        }
+
+        supervisor.scale(3).await.unwrap();
+        for id in 0..3 {
+            assert!(supervisor.wait_running(id).await);
+        }
+        assert_eq!(running(&supervisor).await, [0, 1, 2]);
+        assert!(supervisor.add().await.is_err());
+
+        // The newest are drained first
+        supervisor.scale(1).await.unwrap();
+        assert_eq!(supervisor.count(), 1);
+        assert_eq!(running(&supervisor).await, [0]);
+        until(|| states(&supervisor) == [(0, State::Running)]).await;
+
+        // The old worker is drained once its replacement runs
+        supervisor.reload().unwrap();
+        until(|| states(&supervisor) == [(3, State::Running)]).await;
+        assert_eq!(running(&supervisor).await, [3]);
+
+        supervisor.remove().await.unwrap();
+        assert_eq!(supervisor.count(), 0);
+        assert!(supervisor.remove().await.is_ok());
+        assert!(supervisor.scale(4).await.is_err());
+        remove_sockets(&supervisor);
+    }
+}
@@ -0,0 +1,38 @@
+use futures_util::{SinkExt, StreamExt};
+use messages::codec::{ClientCodec, CodecError, HandshakeCodec};
+use messages::types::{Capabilities, Hello};
+use tokio::net::UnixStream;
+use tokio_util::codec::Framed;
+
+/// Protocol features the front end knows how to handle.
+pub const CAPABILITIES: Capabilities = Capabilities::STREAMING
+    .with(Capabilities::FILES)
+    .with(Capabilities::TRAILERS)
+    .with(Capabilities::EARLY_HINTS);
+
+/// A connection to a worker that went through the [`Hello`] handshake.
+pub struct WorkerConnection {
+    pub framed: Framed<UnixStream, ClientCodec>,
+    pub capabilities: Capabilities,
+}
+
+impl WorkerConnection {
+    pub async fn connect(sock_file: &str, max_frame_size: usize) -> Result<Self, CodecError> {
+        let stream = UnixStream::connect(sock_file).await?;
+        let mut framed = Framed::new(stream, HandshakeCodec::new(max_frame_size));
+        let hello = Hello::new(CAPABILITIES);
+
+        framed.send(hello).await?;
+
+        let remote = match framed.next().await {
+            Some(remote) => remote?,
+            None => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        };
+        let capabilities = hello.negotiate(&remote)?;
+
+        Ok(Self {
+            framed: framed.map_codec(|_| ClientCodec::new(max_frame_size)),
+            capabilities,
+        })
+    }
+}
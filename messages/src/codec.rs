@@ -0,0 +1,302 @@
+use std::{fmt::Display, io, marker::PhantomData};
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::types::{ASGIMessages, HandshakeError, Hello, WorkerRequest};
+
+/// Size of the big-endian length prefix in front of every frame.
+const LENGTH_PREFIX_SIZE: usize = 4;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    FrameTooLarge { size: usize, max: usize },
+    Serialization(bincode::Error),
+    Handshake(HandshakeError),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Io(err) => write!(f, "I/O error: {}", err),
+            CodecError::FrameTooLarge { size, max } => {
+                write!(f, "Frame of {} bytes exceeds the maximum of {}", size, max)
+            }
+            CodecError::Serialization(err) => write!(f, "Invalid frame payload: {}", err),
+            CodecError::Handshake(err) => write!(f, "Handshake failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Io(err) => Some(err),
+            CodecError::FrameTooLarge { .. } => None,
+            CodecError::Serialization(err) => Some(err),
+            CodecError::Handshake(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+impl From<HandshakeError> for CodecError {
+    fn from(err: HandshakeError) -> Self {
+        CodecError::Handshake(err)
+    }
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(err: bincode::Error) -> Self {
+        CodecError::Serialization(err)
+    }
+}
+
+/// Frames bincode encoded messages behind a 4 byte big-endian length, decoding `In` and
+/// encoding `Out`.
+pub struct MessageCodec<In, Out> {
+    max_frame_size: usize,
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+/// Codec for the front end side of a worker socket.
+pub type ClientCodec = MessageCodec<ASGIMessages, WorkerRequest>;
+/// Codec for the worker side of a worker socket.
+pub type WorkerCodec = MessageCodec<WorkerRequest, ASGIMessages>;
+/// Codec for the [`Hello`] frames both sides exchange before anything else.
+pub type HandshakeCodec = MessageCodec<Hello, Hello>;
+
+impl<In, Out> MessageCodec<In, Out> {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            _messages: PhantomData,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl<In, Out> Default for MessageCodec<In, Out> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl<In: DeserializeOwned, Out> Decoder for MessageCodec<In, Out> {
+    type Item = In;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let mut length_bytes = [0u8; LENGTH_PREFIX_SIZE];
+        length_bytes.copy_from_slice(&src[..LENGTH_PREFIX_SIZE]);
+        let length = u32::from_be_bytes(length_bytes) as usize;
+
+        // Checked before buffering anything, a corrupt length must not turn into a huge
+        // allocation
+        if length > self.max_frame_size {
+            return Err(CodecError::FrameTooLarge {
+                size: length,
+                max: self.max_frame_size,
+            });
+        }
+
+        if src.len() < LENGTH_PREFIX_SIZE + length {
+            src.reserve(LENGTH_PREFIX_SIZE + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_PREFIX_SIZE);
+        let payload = src.split_to(length);
+
+        Ok(Some(bincode::deserialize(&payload)?))
+    }
+
+    /// Like the default, but a stream that ends partway through a frame is reported as
+    /// [`io::ErrorKind::UnexpectedEof`] rather than as a generic error.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Stream ended in the middle of a frame",
+            )
+            .into()),
+        }
+    }
+}
+
+impl<In, Out: Serialize> Encoder<Out> for MessageCodec<In, Out> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Out, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let length = bincode::serialized_size(&item)? as usize;
+
+        if length > self.max_frame_size {
+            return Err(CodecError::FrameTooLarge {
+                size: length,
+                max: self.max_frame_size,
+            });
+        }
+
+        dst.reserve(LENGTH_PREFIX_SIZE + length);
+        dst.put_u32(length as u32);
+        bincode::serialize_into(dst.writer(), &item)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        HttpMethod, HttpResponseBody, HttpResponseStart, HttpResponseTrailers, ParsedRequest,
+        TraceContext, Uri,
+    };
+
+    fn request() -> WorkerRequest {
+        WorkerRequest::Http(Box::new(ParsedRequest::new(
+            vec![(b"cookie".to_vec(), b"a=1".to_vec())],
+            HttpMethod::Extension("PROPFIND".to_string()),
+            b"body".to_vec(),
+            Uri::new(None, "/a%20b".to_string(), Some("x=1".to_string())),
+            String::new(),
+            "abc".to_string(),
+            TraceContext::default(),
+        )))
+    }
+
+    #[test]
+    fn round_trips_requests() {
+        let mut buf = BytesMut::new();
+        ClientCodec::default().encode(request(), &mut buf).unwrap();
+
+        let decoded = match WorkerCodec::default().decode(&mut buf).unwrap() {
+            Some(WorkerRequest::Http(request)) => request,
+            other => panic!("unexpected request: {:?}", other),
+        };
+
+        assert_eq!(
+            decoded.method,
+            HttpMethod::Extension("PROPFIND".to_string())
+        );
+        assert_eq!(decoded.headers, vec![(b"cookie".to_vec(), b"a=1".to_vec())]);
+        assert_eq!(decoded.body, b"body");
+        assert_eq!(decoded.uri.path(), "/a b");
+        assert_eq!(decoded.request_id, "abc");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn round_trips_responses_split_across_reads() {
+        let mut start = HttpResponseStart::new("http.response.start", 201);
+        start.add_header(b"content-type", b"text/plain");
+        start.set_trailers(true);
+        let mut trailers = HttpResponseTrailers::new(false);
+        trailers.add_header(b"grpc-status", b"0");
+
+        let mut encoded = BytesMut::new();
+        let mut codec = WorkerCodec::default();
+        codec
+            .encode(ASGIMessages::HttpResponseStart(start), &mut encoded)
+            .unwrap();
+        codec
+            .encode(
+                ASGIMessages::HttpResponseBody(HttpResponseBody::new(b"hi".to_vec(), false)),
+                &mut encoded,
+            )
+            .unwrap();
+        codec
+            .encode(ASGIMessages::HttpResponseTrailers(trailers), &mut encoded)
+            .unwrap();
+
+        let mut decoder = ClientCodec::default();
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+
+        for byte in encoded {
+            buf.put_u8(byte);
+            if let Some(message) = decoder.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+
+        use ASGIMessages::{
+            HttpResponseBody as Body, HttpResponseStart as Start, HttpResponseTrailers as Trailers,
+        };
+        match decoded.as_slice() {
+            [Start(start), Body(body), Trailers(trailers)] => {
+                assert_eq!(start.status, 201);
+                assert_eq!(start.headers[0].1, b"text/plain");
+                assert!(start.trailers);
+                assert_eq!(body.body(), b"hi");
+                assert_eq!(trailers.headers[0].0, b"grpc-status");
+                assert!(!trailers.more_trailers);
+            }
+            other => panic!("unexpected messages: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_truncated_frames_as_unexpected_eof() {
+        let mut buf = BytesMut::new();
+        ClientCodec::default().encode(request(), &mut buf).unwrap();
+        buf.truncate(buf.len() - 1);
+
+        let err = WorkerCodec::default().decode_eof(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            CodecError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(WorkerCodec::default()
+            .decode_eof(&mut BytesMut::new())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_frames_over_the_limit() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(u32::MAX);
+
+        let err = WorkerCodec::new(1024).decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            CodecError::FrameTooLarge {
+                size: 4294967295,
+                max: 1024
+            }
+        ));
+
+        let err = ClientCodec::new(8)
+            .encode(request(), &mut BytesMut::new())
+            .unwrap_err();
+        assert!(matches!(err, CodecError::FrameTooLarge { max: 8, .. }));
+    }
+
+    #[test]
+    fn rejects_garbage_payloads() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(2);
+        buf.put_slice(&[0xff, 0xff]);
+
+        let err = ClientCodec::default().decode(&mut buf).unwrap_err();
+        assert!(matches!(err, CodecError::Serialization(_)));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod codec;
 pub mod types;
 
 pub fn add(left: u64, right: u64) -> u64 {
@@ -1,22 +1,151 @@
-use std::{collections::HashMap, fmt::Display, sync::mpsc::Sender};
+use std::fmt::Display;
+use std::path::PathBuf;
 
-use hyper::{body::Bytes, HeaderMap, Method};
+use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize,Deserialize)]
+/// Version of the messages exchanged over the worker socket, bumped on any change to their
+/// encoding.
+pub const PROTOCOL_VERSION: u32 = 10;
+const HELLO_MAGIC: [u8; 4] = *b"FRCN";
+
+/// Seconds an overloaded server, front end or worker, tells clients to wait before retrying.
+pub const RETRY_AFTER_SECONDS: &str = "1";
+
+/// Optional protocol features, as a bit set so unknown bits from newer peers are ignored.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const STREAMING: Capabilities = Capabilities(1);
+    pub const WEBSOCKETS: Capabilities = Capabilities(1 << 1);
+    pub const TRAILERS: Capabilities = Capabilities(1 << 2);
+    /// Body parts the front end reads from disk itself, see [`HttpResponseFile`]
+    pub const FILES: Capabilities = Capabilities(1 << 3);
+    /// `103 Early Hints` sent ahead of the response, see [`HttpResponseEarlyHint`]
+    pub const EARLY_HINTS: Capabilities = Capabilities(1 << 4);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn with(self, other: Capabilities) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// First frame sent by each side of a worker connection. Its layout must never change so
+/// that peers of any version can still read it and report the mismatch.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    magic: [u8; 4],
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            magic: HELLO_MAGIC,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// Checks the peer's hello against ours, returning the capabilities both sides support.
+    pub fn negotiate(&self, remote: &Hello) -> Result<Capabilities, HandshakeError> {
+        if remote.magic != HELLO_MAGIC {
+            return Err(HandshakeError::NotFerricorn);
+        }
+
+        if remote.protocol_version != self.protocol_version {
+            return Err(HandshakeError::VersionMismatch {
+                local: self.protocol_version,
+                remote: remote.protocol_version,
+            });
+        }
+
+        Ok(self.capabilities.intersection(remote.capabilities))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    NotFerricorn,
+    VersionMismatch { local: u32, remote: u32 },
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::NotFerricorn => {
+                f.write_str("Peer is not speaking the ferricorn protocol")
+            }
+            HandshakeError::VersionMismatch { local, remote } => write!(
+                f,
+                "Protocol version {} is incompatible with the peer's {}, \
+                 asgi and worker must come from the same build",
+                local, remote
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// What the front end sends a worker.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WorkerRequest {
+    Http(Box<ParsedRequest>),
+    /// Body of the request being handled, when it was held back and the worker asked for it
+    /// with [`ASGIMessages::ReadBody`]
+    Body(Vec<u8>),
+    /// Asks for a [`Heartbeat`] back
+    Heartbeat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ASGIMessages {
+    HttpResponseEarlyHint(HttpResponseEarlyHint),
     HttpResponseStart(HttpResponseStart),
     HttpResponseBody(HttpResponseBody),
+    HttpResponseFile(HttpResponseFile),
+    HttpResponseTrailers(HttpResponseTrailers),
+    /// The app wants the request body that was held back, see [`ParsedRequest::body_deferred`]
+    ReadBody,
+    Heartbeat(Heartbeat),
+}
+
+/// A worker's report on itself.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub pid: u32,
+    /// Requests waiting for the app
+    pub queue_depth: u64,
+    /// Resident memory, when the platform reports it
+    pub rss_bytes: Option<u64>,
+    /// The app finished its lifespan startup, or doesn't take part in the lifespan protocol
+    pub started: bool,
 }
 
-#[derive(Debug, Serialize,Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HttpResponseBody {
     pub body: Vec<u8>,
+    /// More of the body follows in further messages
+    pub more_body: bool,
 }
 
 impl HttpResponseBody {
-    pub fn new(body: Vec<u8>) -> Self {
-        Self { body }
+    pub fn new(body: Vec<u8>, more_body: bool) -> Self {
+        Self { body, more_body }
     }
 
     pub fn body(&self) -> &[u8] {
@@ -24,11 +153,69 @@ impl HttpResponseBody {
     }
 }
 
-#[derive(Debug, Serialize,Deserialize)]
+/// Part of a response body the front end sends straight from a file, for the
+/// `http.response.pathsend` and `http.response.zerocopysend` extensions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HttpResponseFile {
+    pub path: PathBuf,
+    pub offset: u64,
+    /// Bytes to send, up to the end of the file when missing
+    pub count: Option<u64>,
+    /// More of the body follows in further messages
+    pub more_body: bool,
+}
+
+impl HttpResponseFile {
+    pub fn new(path: PathBuf, offset: u64, count: Option<u64>, more_body: bool) -> Self {
+        Self {
+            path,
+            offset,
+            count,
+            more_body,
+        }
+    }
+}
+
+/// Links the client may preload while the app is still working on the response, sent as
+/// Link headers of a `103 Early Hints` response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HttpResponseEarlyHint {
+    pub links: Vec<Vec<u8>>,
+}
+
+impl HttpResponseEarlyHint {
+    pub fn new(links: Vec<Vec<u8>>) -> Self {
+        Self { links }
+    }
+}
+
+/// Trailing headers sent after the body, when the response start announced them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HttpResponseTrailers {
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    /// More trailers follow in further messages
+    pub more_trailers: bool,
+}
+
+impl HttpResponseTrailers {
+    pub fn new(more_trailers: bool) -> Self {
+        Self {
+            headers: Vec::new(),
+            more_trailers,
+        }
+    }
+
+    pub fn add_header(&mut self, name: &[u8], value: &[u8]) {
+        self.headers.push((name.to_vec(), value.to_vec()));
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HttpResponseStart {
     pub response_type: String,
     pub status: u16,
     pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    /// [`HttpResponseTrailers`] follow the last body message
     pub trailers: bool,
 }
 
@@ -51,60 +238,95 @@ impl HttpResponseStart {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum HttpMethod {
     POST,
     GET,
+    PUT,
     PATCH,
     OPTIONS,
     DELETE,
     HEAD,
+    CONNECT,
+    TRACE,
+    /// Any other method token (e.g. WebDAV's `PROPFIND`), kept exactly as received
+    Extension(String),
 }
 
-impl Display for HttpMethod {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let strs_val = match self {
+impl HttpMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
             HttpMethod::POST => "POST",
             HttpMethod::GET => "GET",
+            HttpMethod::PUT => "PUT",
             HttpMethod::PATCH => "PATCH",
             HttpMethod::OPTIONS => "OPTIONS",
             HttpMethod::DELETE => "DELETE",
             HttpMethod::HEAD => "HEAD",
-        };
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::Extension(method) => method,
+        }
+    }
+}
 
-        f.write_str(strs_val)
+impl Display for HttpMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
 impl TryFrom<String> for HttpMethod {
     type Error = String;
 
+    /// Method names are case-sensitive (RFC 9110, section 9.1), so `get` is an extension
+    /// method rather than `GET`.
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        match value.to_uppercase().as_ref() {
+        match value.as_ref() {
             "POST" => Ok(Self::POST),
             "GET" => Ok(Self::GET),
+            "PUT" => Ok(Self::PUT),
             "PATCH" => Ok(Self::PATCH),
             "OPTIONS" => Ok(Self::OPTIONS),
             "DELETE" => Ok(Self::DELETE),
             "HEAD" => Ok(Self::HEAD),
+            "CONNECT" => Ok(Self::CONNECT),
+            "TRACE" => Ok(Self::TRACE),
+            _ if is_token(&value) => Ok(Self::Extension(value)),
             _ => Err(format!("Invalid HTTPMethod: {}", value)),
         }
     }
 }
 
+/// Characters besides ASCII letters and digits allowed in a `token` (RFC 9110, section 5.6.2)
+const TOKEN_SYMBOLS: &[u8] = b"!#$%&'*+-.^_`|~";
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || TOKEN_SYMBOLS.contains(&b))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Uri {
     scheme: Option<String>,
     path: String,
-    query_string: Option<String>,
+    raw_path: Vec<u8>,
+    query_string: Vec<u8>,
 }
 
 impl Uri {
-    pub fn new(scheme: Option<String>, path: String, query_string: Option<String>) -> Self {
+    /// Builds the URI from the request target as sent by the client; `raw_path` is
+    /// percent-decoded into [`Uri::path`] and kept untouched in [`Uri::raw_path`].
+    pub fn new(scheme: Option<String>, raw_path: String, query_string: Option<String>) -> Self {
         Self {
             scheme,
-            path,
-            query_string,
+            path: percent_decode_str(&raw_path)
+                .decode_utf8_lossy()
+                .into_owned(),
+            raw_path: raw_path.into_bytes(),
+            query_string: query_string.map(String::into_bytes).unwrap_or_default(),
         }
     }
 
@@ -116,31 +338,126 @@ impl Uri {
         &self.path
     }
 
-    pub fn query_string(&self) -> Option<&str> {
-        self.query_string.as_deref()
+    pub fn raw_path(&self) -> &[u8] {
+        &self.raw_path
+    }
+
+    /// The query string without the leading `?`, empty when the request had none.
+    pub fn query_string(&self) -> &[u8] {
+        &self.query_string
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ParsedRequest {
-    pub headers: HashMap<String, String>,
+    /// Header names and values as received, lowercased names, duplicates kept
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
     pub method: HttpMethod,
     pub body: Vec<u8>,
+    /// The front end holds the body until the app asks for it, `body` is empty then
+    pub body_deferred: bool,
     pub uri: Uri,
+    /// Prefix the app is mounted under, empty or starting with `/` and without a trailing one
+    pub root_path: String,
+    /// Id the front end logged the request under, for tagging the worker's logs
+    pub request_id: String,
+    /// Trace the worker's spans for this request belong to
+    pub trace_context: TraceContext,
 }
 
 impl ParsedRequest {
     pub fn new(
-        headers: HashMap<String, String>,
+        headers: Vec<(Vec<u8>, Vec<u8>)>,
         method: HttpMethod,
         body: Vec<u8>,
         uri: Uri,
+        root_path: String,
+        request_id: String,
+        trace_context: TraceContext,
     ) -> Self {
         Self {
             headers,
             method,
             body,
+            body_deferred: false,
             uri,
+            root_path,
+            request_id,
+            trace_context,
         }
     }
 }
+
+/// W3C trace context headers, see <https://www.w3.org/TR/trace-context/>
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct TraceContext {
+    pub traceparent: Option<String>,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    pub fn is_empty(&self) -> bool {
+        self.traceparent.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_method_extension_is_kept_verbatim() {
+        let method = HttpMethod::try_from("PROPFIND".to_string()).unwrap();
+        assert_eq!(method, HttpMethod::Extension("PROPFIND".to_string()));
+        assert_eq!(method.to_string(), "PROPFIND");
+
+        let method = HttpMethod::try_from("get".to_string()).unwrap();
+        assert_eq!(method.to_string(), "get");
+
+        assert_eq!(HttpMethod::try_from("PUT".to_string()), Ok(HttpMethod::PUT));
+    }
+
+    #[test]
+    fn hello_negotiates_common_capabilities() {
+        let ours = Hello::new(Capabilities::STREAMING.with(Capabilities::TRAILERS));
+        let theirs = Hello::new(Capabilities::STREAMING.with(Capabilities::WEBSOCKETS));
+
+        let common = ours.negotiate(&theirs).unwrap();
+        assert!(common.contains(Capabilities::STREAMING));
+        assert!(!common.contains(Capabilities::TRAILERS));
+        assert!(!common.contains(Capabilities::WEBSOCKETS));
+
+        let mut newer = Hello::new(Capabilities::empty());
+        newer.protocol_version = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            ours.negotiate(&newer),
+            Err(HandshakeError::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn uri_decodes_path_and_keeps_raw_forms() {
+        let uri = Uri::new(
+            None,
+            "/caf%C3%A9/a%2Fb%20c".to_string(),
+            Some("q=%20x".to_string()),
+        );
+
+        assert_eq!(uri.path(), "/café/a/b c");
+        assert_eq!(uri.raw_path(), b"/caf%C3%A9/a%2Fb%20c");
+        assert_eq!(uri.query_string(), b"q=%20x");
+
+        let uri = Uri::new(None, "/".to_string(), None);
+        assert_eq!(uri.query_string(), b"");
+    }
+
+    #[test]
+    fn http_method_rejects_invalid_tokens() {
+        assert!(HttpMethod::try_from("".to_string()).is_err());
+        assert!(HttpMethod::try_from("GET /".to_string()).is_err());
+        assert!(HttpMethod::try_from("M(ETHOD)".to_string()).is_err());
+    }
+}
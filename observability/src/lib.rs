@@ -0,0 +1,5 @@
+//! Logging and tracing shared by the front end and the workers, kept out of the `messages`
+//! crate so that the protocol doesn't depend on them.
+
+pub mod logging;
+pub mod telemetry;
@@ -0,0 +1,66 @@
+use std::{
+    fmt::Display,
+    io::{stderr, IsTerminal},
+    str::FromStr,
+};
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+use crate::telemetry::Telemetry;
+
+/// How log lines are written, the same for the front end and the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unknown log format {}, expected pretty or json",
+                value
+            )),
+        }
+    }
+}
+
+/// Sends everything logged at `level` or above to stderr, stdout is left to the access log.
+/// Request spans are also exported through `telemetry`, whatever the level.
+pub fn init(level: LevelFilter, format: LogFormat, telemetry: Option<&Telemetry>) {
+    let spans = telemetry.map(Telemetry::layer);
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(stderr)
+        .with_ansi(stderr().is_terminal());
+    let registry = tracing_subscriber::registry().with(spans);
+
+    match format {
+        LogFormat::Pretty => registry.with(fmt.with_filter(level)).init(),
+        LogFormat::Json => registry
+            .with(fmt.json().flatten_event(true).with_filter(level))
+            .init(),
+    }
+}
@@ -0,0 +1,189 @@
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+    Context,
+};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::filter_fn, registry::LookupSpan, Layer};
+
+use messages::types::TraceContext;
+
+/// Target of the spans that are exported, the others only give context to log lines.
+pub const TARGET: &str = "ferricorn::trace";
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// Exports spans over OTLP/HTTP until dropped, which flushes whatever is still buffered.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// `endpoint` is the collector's full traces URL, e.g. `http://localhost:4318/v1/traces`.
+    pub fn new(endpoint: &str, service_name: &'static str) -> Result<Self, ExporterBuildError> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build();
+
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Self { provider })
+    }
+
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.provider.tracer("ferricorn"))
+            .with_filter(filter_fn(|metadata| metadata.target() == TARGET))
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        let _ = self.provider.shutdown();
+    }
+}
+
+/// Makes `span` a child of the trace `carrier` belongs to, if any.
+pub fn set_parent(span: &Span, carrier: &TraceContext) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&Extract(carrier)));
+    let _ = span.set_parent(parent);
+}
+
+/// The context to hand on so the next hop's spans become children of `span`, empty when no
+/// spans are exported.
+pub fn trace_context(span: &Span) -> TraceContext {
+    let mut carrier = TraceContext::default();
+    let context: Context = span.context();
+
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut Inject(&mut carrier))
+    });
+
+    carrier
+}
+
+/// Reads the propagated headers out of a [`TraceContext`].
+struct Extract<'a>(&'a TraceContext);
+
+/// Writes the propagated headers into a [`TraceContext`].
+struct Inject<'a>(&'a mut TraceContext);
+
+impl Extractor for Extract<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            TRACEPARENT => self.0.traceparent.as_deref(),
+            TRACESTATE => self.0.tracestate.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        vec![TRACEPARENT, TRACESTATE]
+    }
+}
+
+impl Injector for Inject<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        match key {
+            TRACEPARENT => self.0.traceparent = Some(value),
+            TRACESTATE => self.0.tracestate = Some(value),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    /// Stands in for a collector, sends the body of every request it gets.
+    fn collector() -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+                let _ = tx.send(body);
+            }
+        });
+
+        (endpoint, rx)
+    }
+
+    #[test]
+    fn exports_spans_in_the_trace_they_are_handed() {
+        let (endpoint, exported) = collector();
+        let telemetry = Telemetry::new(&endpoint, "ferricorn-test").unwrap();
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+
+        let incoming = TraceContext {
+            traceparent: Some(format!("00-{}-00f067aa0ba902b7-01", TRACE_ID)),
+            tracestate: None,
+        };
+        let outgoing = tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!(target: TARGET, "request");
+            set_parent(&span, &incoming);
+
+            trace_context(&span)
+        });
+        // Flushes the span
+        drop(telemetry);
+
+        // Same trace, with the span as the parent of the next hop
+        let traceparent = outgoing.traceparent.unwrap();
+        assert!(traceparent.starts_with(&format!("00-{}-", TRACE_ID)));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+        let trace_id = (0..TRACE_ID.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&TRACE_ID[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        let body = exported.recv().unwrap();
+        assert!(body.windows(trace_id.len()).any(|bytes| bytes == trace_id));
+    }
+}
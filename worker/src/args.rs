@@ -1,6 +1,9 @@
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 use clap::Parser;
+use messages::codec::DEFAULT_MAX_FRAME_SIZE;
+use observability::logging::LogFormat;
+use tracing::level_filters::LevelFilter;
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -14,4 +17,18 @@ pub struct Arguments {
     pub module: String,
     #[arg(short, long, value_name = "SOCK_FILE", default_value = "/tmp/worker-1")]
     pub sock: PathBuf,
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    pub max_frame_size: usize,
+    /// Requests waiting for Python before new ones are turned away with a 503
+    #[arg(long, value_name = "COUNT", default_value_t = 64)]
+    pub queue_size: usize,
+    /// Position of this worker among the server's workers, added to its log lines
+    #[arg(long, value_name = "INDEX", default_value_t = 0)]
+    pub index: usize,
+    #[arg(long, value_name = "LEVEL", default_value_t = LevelFilter::INFO)]
+    pub log_level: LevelFilter,
+    #[arg(long, value_name = "FORMAT", default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
 }
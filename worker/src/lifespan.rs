@@ -0,0 +1,203 @@
+use std::sync::{Arc, Mutex};
+
+use pyo3::{
+    types::{PyAnyMethods, PyCFunction, PyDict, PyDictMethods, PyList, PyString, PyTuple},
+    Bound, Py, PyAny, PyResult, Python,
+};
+use tracing::{error, info, warn};
+
+/// How the app's lifespan startup went.
+pub enum Startup {
+    /// With what it takes to shut the app down later
+    Complete(Lifespan),
+    /// The app raised or returned on the lifespan scope, it's served without one
+    Unsupported,
+    Failed(String),
+}
+
+/// The app's lifespan task once its startup completed, waiting for the shutdown event.
+pub struct Lifespan {
+    task: Py<PyAny>,
+    /// The app's `receive()` call the shutdown event goes to
+    waiting: Arc<Mutex<Option<Py<PyAny>>>>,
+    /// Resolved with `None` on shutdown.complete, or the message on shutdown.failed
+    shutdown_reported: Py<PyAny>,
+}
+
+/// Runs the ASGI lifespan startup on `event_loop`, the loop the requests are run on later,
+/// and returns once the app reported how it went.
+///
+/// What the app puts in `state` is copied into the scope of every request.
+pub fn startup(
+    py: Python<'_>,
+    app: &Bound<'_, PyAny>,
+    event_loop: &Bound<'_, PyAny>,
+    state: &Bound<'_, PyDict>,
+) -> PyResult<Startup> {
+    let scope = PyDict::new(py);
+    scope.set_item("type", "lifespan")?;
+    let asgi = PyDict::new(py);
+    asgi.set_item("version", "3.0")?;
+    asgi.set_item("spec_version", "2.0")?;
+    scope.set_item("asgi", asgi)?;
+    scope.set_item("state", state)?;
+
+    // Resolved with `None` on *.complete, or the message on *.failed
+    let startup_reported = event_loop.call_method0("create_future")?;
+    let shutdown_reported = event_loop.call_method0("create_future")?;
+
+    // The first receive() gets the startup event, the next one waits for the shutdown
+    let asked = Arc::new(Mutex::new(false));
+    let waiting = Arc::new(Mutex::new(None));
+    let waiting_for_shutdown = Arc::clone(&waiting);
+    let receive_callback = move |_args: &Bound<'_, PyTuple>,
+                                 _kwargs: Option<&Bound<'_, PyDict>>|
+          -> PyResult<Py<PyAny>> {
+        Python::with_gil(|py| {
+            let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+            let future = event_loop.call_method0("create_future")?;
+
+            let mut asked = asked.lock().unwrap();
+            match *asked {
+                false => {
+                    *asked = true;
+                    future.call_method1("set_result", (event(py, "lifespan.startup")?,))?;
+                }
+                true => *waiting_for_shutdown.lock().unwrap() = Some(future.clone().unbind()),
+            }
+
+            Ok(future.unbind())
+        })
+    };
+
+    let reports = (
+        startup_reported.clone().unbind(),
+        shutdown_reported.clone().unbind(),
+    );
+    let send_callback = move |args: &Bound<'_, PyTuple>,
+                              _kwargs: Option<&Bound<'_, PyDict>>|
+          -> PyResult<Py<PyAny>> {
+        Python::with_gil(|py| {
+            let message = args.get_item(0)?;
+            let message_type = message.get_item("type")?.extract::<String>()?;
+
+            let (report, failed) = match message_type.as_str() {
+                "lifespan.startup.complete" => (&reports.0, false),
+                "lifespan.startup.failed" => (&reports.0, true),
+                "lifespan.shutdown.complete" => (&reports.1, false),
+                "lifespan.shutdown.failed" => (&reports.1, true),
+                _ => {
+                    warn!(
+                        message_type,
+                        "Ignoring unsupported lifespan message from the app"
+                    );
+                    return resolved(py);
+                }
+            };
+            let result = match (failed, message.get_item("message")) {
+                (false, _) => py.None(),
+                (true, Ok(text)) => text.str()?.into_any().unbind(),
+                (true, Err(_)) => PyString::new(py, "").into_any().unbind(),
+            };
+
+            let report = report.bind(py);
+            if !report.call_method0("done")?.is_truthy()? {
+                report.call_method1("set_result", (result,))?;
+            }
+
+            resolved(py)
+        })
+    };
+
+    let receive = PyCFunction::new_closure(py, None, None, receive_callback)?;
+    let send = PyCFunction::new_closure(py, None, None, send_callback)?;
+
+    let coroutine = app.call1((scope, receive, send))?;
+    let task = event_loop.call_method1("create_task", (coroutine,))?;
+
+    match run_until_reported(py, event_loop, &task, &startup_reported)? {
+        Some(None) => Ok(Startup::Complete(Lifespan {
+            task: task.unbind(),
+            waiting,
+            shutdown_reported: shutdown_reported.unbind(),
+        })),
+        Some(Some(message)) => Ok(Startup::Failed(message)),
+        None => {
+            // Like uvicorn, an app that raises or returns on the lifespan scope doesn't
+            // support it
+            let error = task.call_method0("exception")?;
+            match error.is_none() {
+                true => info!("The app returned from the lifespan startup without reporting on it"),
+                false => info!(error = %error, "The app doesn't support the lifespan protocol"),
+            }
+
+            Ok(Startup::Unsupported)
+        }
+    }
+}
+
+impl Lifespan {
+    /// Sends the app the shutdown event and runs `event_loop` until it reported how its
+    /// shutdown went.
+    pub fn shutdown(self, py: Python<'_>, event_loop: &Bound<'_, PyAny>) -> PyResult<()> {
+        // The loop isn't running between requests, the future can be resolved from here
+        if let Some(future) = self.waiting.lock().unwrap().take() {
+            let future = future.bind(py);
+            if !future.call_method0("done")?.is_truthy()? {
+                future.call_method1("set_result", (event(py, "lifespan.shutdown")?,))?;
+            }
+        }
+
+        let task = self.task.bind(py);
+        match run_until_reported(py, event_loop, task, self.shutdown_reported.bind(py))? {
+            Some(None) => info!("The app's lifespan shutdown completed"),
+            Some(Some(message)) => error!(reason = message, "The app's lifespan shutdown failed"),
+            None => {
+                let error = task.call_method0("exception")?;
+                if !error.is_none() {
+                    error!(error = %error, "Exception in the lifespan shutdown");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `event_loop` until `report` is resolved or `task` finished first, returning the
+/// report: `None` when the app succeeded, the app's message when it failed.
+fn run_until_reported(
+    py: Python<'_>,
+    event_loop: &Bound<'_, PyAny>,
+    task: &Bound<'_, PyAny>,
+    report: &Bound<'_, PyAny>,
+) -> PyResult<Option<Option<String>>> {
+    let asyncio = py.import("asyncio")?;
+
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("return_when", asyncio.getattr("FIRST_COMPLETED")?)?;
+    let either = PyList::new(py, [task, report])?;
+    let wait = asyncio.call_method("wait", (either,), Some(&kwargs))?;
+    event_loop.call_method1("run_until_complete", (wait,))?;
+
+    if !report.call_method0("done")?.is_truthy()? {
+        return Ok(None);
+    }
+
+    Ok(Some(report.call_method0("result")?.extract()?))
+}
+
+fn event<'py>(py: Python<'py>, event_type: &str) -> PyResult<Bound<'py, PyDict>> {
+    let event = PyDict::new(py);
+    event.set_item("type", event_type)?;
+
+    Ok(event)
+}
+
+/// What `send()` returns, the message is handled by the time it's awaited.
+fn resolved(py: Python<'_>) -> PyResult<Py<PyAny>> {
+    let future = py.import("asyncio")?.call_method0("Future")?;
+    future.call_method1("set_result", (py.None(),))?;
+
+    Ok(future.unbind())
+}
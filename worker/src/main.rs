@@ -1,66 +1,139 @@
 use args::Arguments;
 use clap::Parser;
-use crossbeam_channel::unbounded;
-use messages::types::{ASGIMessages, ParsedRequest};
-use py_process::PythonProcess;
-use std::{fs::File, process::exit, sync::Arc};
+use crossbeam_channel::{bounded, TrySendError};
+use futures_util::{SinkExt, StreamExt};
+use messages::codec::{CodecError, HandshakeCodec, WorkerCodec};
+use messages::types::{
+    ASGIMessages, Capabilities, Heartbeat, Hello, HttpResponseBody, HttpResponseStart,
+    WorkerRequest, RETRY_AFTER_SECONDS,
+};
+use observability::logging;
+use observability::telemetry::Telemetry;
+use py_process::{Job, PythonProcess, PythonRequest};
+use std::process::{self, exit};
+use std::sync::mpsc::SyncSender;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Interest},
-    net::UnixListener,
+    net::{UnixListener, UnixStream},
     signal::{unix::signal, unix::SignalKind},
+    sync::mpsc::unbounded_channel,
+    time::timeout,
 };
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
 pub mod args;
+pub mod lifespan;
+pub mod py_logging;
 pub mod py_process;
 
+/// Protocol features this worker knows how to handle.
+const CAPABILITIES: Capabilities = Capabilities::STREAMING
+    .with(Capabilities::FILES)
+    .with(Capabilities::TRAILERS)
+    .with(Capabilities::EARLY_HINTS);
+
+/// How long the app gets for its lifespan shutdown before the worker exits anyway.
+const LIFESPAN_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct Connection {
     pub id: u32,
-    pub request: ParsedRequest,
+    pub request: PythonRequest,
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Arguments::parse();
+    let telemetry = match &cli.otlp_endpoint {
+        Some(endpoint) => match Telemetry::new(endpoint, "ferricorn-worker") {
+            Ok(telemetry) => Some(telemetry),
+            Err(err) => {
+                eprintln!("Failed to set up the OTLP exporter: {}", err);
+                exit(1)
+            }
+        },
+        None => None,
+    };
+    logging::init(cli.log_level, cli.log_format, telemetry.as_ref());
+    let span = info_span!("worker", index = cli.index, pid = process::id());
 
     let mut signal_terminate = signal(SignalKind::terminate()).unwrap();
     let mut signal_interrupt = signal(SignalKind::interrupt()).unwrap();
 
+    let (listener, started, python_tx) = span.in_scope(|| {
+        info!(sock = %cli.sock.display(), "Listening");
+        let listener = UnixListener::bind(cli.sock.clone()).unwrap();
+
+        let (module, asgi_attr) = cli.module.split_once(":").unwrap();
+
+        // Heartbeats are answered meanwhile, telling the front end the app is still starting
+        let started = Arc::new(AtomicBool::new(false));
+        let python_tx = PythonProcess::start(
+            module.to_owned(),
+            asgi_attr.to_owned(),
+            cli.log_level,
+            Arc::clone(&started),
+        )
+        .unwrap();
+
+        (listener, started, python_tx)
+    });
+
     tokio::select! {
-        _ = signal_terminate.recv() => {
-            if  cli.sock.exists() {
-                std::fs::remove_file(cli.sock).unwrap();
-            }
-            exit(0)
-        },
-        _ = signal_interrupt.recv() => {
-            if  cli.sock.exists() {
-                std::fs::remove_file(cli.sock).unwrap();
-            }
-            exit(0)
+        _ = signal_terminate.recv() => {},
+        _ = signal_interrupt.recv() => {},
+        _ = run_worker(&cli, listener, python_tx.clone(), started).instrument(span.clone()) => {
+            info!("Worker stopped")
         },
-        _ = run_worker(&cli) => { println!("worker finihsed")},
     };
-}
 
-async fn run_worker(cli: &Arguments) {
-    println!("listening to : {:?}", &cli.sock);
-    let listener = UnixListener::bind(cli.sock.clone()).unwrap();
-    let (tx_request, rx_request) = unbounded::<Connection>();
-    let (tx_response, rx_response) = unbounded::<ASGIMessages>();
+    shut_down(python_tx).instrument(span).await;
+    if cli.sock.exists() {
+        std::fs::remove_file(cli.sock).unwrap();
+    }
+    // Flushes the spans of the last requests, exit() skips destructors
+    drop(telemetry);
+    exit(0)
+}
 
-    let (module, asgi_attr) = cli.module.split_once(":").unwrap();
+/// Runs the app's lifespan shutdown once the request being handled is done, giving up
+/// after [`LIFESPAN_SHUTDOWN_TIMEOUT`].
+async fn shut_down(python_tx: SyncSender<Job>) {
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let shutdown = tokio::task::spawn_blocking(move || {
+        python_tx.send(Job::Shutdown(done_tx)).ok()?;
+        done_rx.recv().ok()
+    });
 
-    let python_tx =
-        PythonProcess::start(module.to_owned(), asgi_attr.to_owned(), tx_response).unwrap();
+    if timeout(LIFESPAN_SHUTDOWN_TIMEOUT, shutdown).await.is_err() {
+        warn!(
+            timeout = ?LIFESPAN_SHUTDOWN_TIMEOUT,
+            "The app's lifespan shutdown timed out, exiting without it"
+        );
+    }
+}
 
-    let python_tx = Arc::new(python_tx);
+async fn run_worker(
+    cli: &Arguments,
+    listener: UnixListener,
+    python_tx: SyncSender<Job>,
+    started: Arc<AtomicBool>,
+) {
+    let (tx_request, rx_request) = bounded::<Connection>(cli.queue_size);
 
     let mut conn_id = 0; // Background task to handle Python communication
 
-    tokio::spawn(async move {
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+
         while let Ok(conn) = rx_request.recv() {
-            if let Err(e) = python_tx.send(conn.request) {
-                eprintln!("Failed to send to Python: {}", e);
+            if let Err(e) = python_tx.send(Job::Request(Box::new(conn.request))) {
+                error!(connection = conn.id, error = %e, "Failed to send to Python");
                 break;
             }
         }
@@ -70,124 +143,258 @@ async fn run_worker(cli: &Arguments) {
         match listener.accept().await {
             Ok((stream, _addr)) => {
                 let tx_req = tx_request.clone();
-                let rx_resp = rx_response.clone();
+                let started = Arc::clone(&started);
                 let current_id = conn_id;
+                let max_frame_size = cli.max_frame_size;
                 conn_id += 1;
 
-                tokio::spawn(async move {
-                    handle_connection(stream, tx_req, rx_resp, current_id).await
-                });
+                tokio::spawn(
+                    handle_connection(stream, tx_req, current_id, max_frame_size, started)
+                        .instrument(info_span!("connection", id = current_id)),
+                );
             }
-            Err(err) => eprintln!("Failed to accept connection: {}", err),
+            Err(err) => error!(error = %err, "Failed to accept connection"),
         }
     }
 }
 
+/// Answers the front end's [`Hello`] with ours, even when they turn out to be incompatible
+/// so that the other side can report why it's being refused.
+async fn handshake(
+    framed: &mut Framed<UnixStream, HandshakeCodec>,
+) -> Result<Capabilities, CodecError> {
+    let remote = match framed.next().await {
+        Some(remote) => remote?,
+        None => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+    };
+    let hello = Hello::new(CAPABILITIES);
+
+    framed.send(hello).await?;
+
+    Ok(hello.negotiate(&remote)?)
+}
+
+fn heartbeat(
+    tx_request: &crossbeam_channel::Sender<Connection>,
+    started: &AtomicBool,
+) -> Heartbeat {
+    Heartbeat {
+        pid: process::id(),
+        queue_depth: tx_request.len() as u64,
+        rss_bytes: rss_bytes(),
+        started: started.load(Ordering::Relaxed),
+    }
+}
+
+/// Resident memory of this process, from `/proc` so only on Linux.
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kib * 1024)
+}
+
 async fn handle_connection(
-    mut stream: tokio::net::UnixStream,
+    stream: UnixStream,
     tx_request: crossbeam_channel::Sender<Connection>,
-    rx_response: crossbeam_channel::Receiver<ASGIMessages>,
     conn_id: u32,
+    max_frame_size: usize,
+    started: Arc<AtomicBool>,
 ) {
+    let mut framed = Framed::new(stream, HandshakeCodec::new(max_frame_size));
+
+    let capabilities = match handshake(&mut framed).await {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            warn!(error = %e, "Refusing connection");
+            return;
+        }
+    };
+    let streaming = capabilities.contains(Capabilities::STREAMING);
+
+    let mut framed = framed.map_codec(|_| WorkerCodec::new(max_frame_size));
+
     loop {
-        let mut len_bytes = [0u8; 4];
-        match stream.read_exact(&mut len_bytes).await {
-            Ok(_) => (),
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                // Clean exit - client closed connection
-                println!("Client disconnected (connection {})", conn_id);
+        let request = match framed.next().await {
+            Some(Ok(WorkerRequest::Http(request))) => *request,
+            Some(Ok(WorkerRequest::Heartbeat)) => {
+                let heartbeat = ASGIMessages::Heartbeat(heartbeat(&tx_request, &started));
+
+                if let Err(e) = framed.send(heartbeat).await {
+                    error!(error = %e, "Error sending heartbeat");
+                    break;
+                }
+                continue;
+            }
+            Some(Ok(WorkerRequest::Body(_))) => {
+                error!("Received a request body nobody asked for");
                 break;
             }
-            Err(e) => {
-                eprintln!("Error reading from client (connection {}): {}", conn_id, e);
+            None => {
+                // Clean exit - client closed connection
+                debug!("Client disconnected");
                 break;
             }
-        }
-
-        let len = u32::from_be_bytes(len_bytes) as usize;
-        let mut buffer = vec![0u8; len];
-
-        match stream.read_exact(&mut buffer).await {
-            Ok(_) => (),
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                println!("Client disconnected mid-message (connection {})", conn_id);
+            Some(Err(CodecError::Io(e))) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                warn!("Client disconnected mid-message");
                 break;
             }
-            Err(e) => {
-                eprintln!("Error reading message body (connection {}): {}", conn_id, e);
+            Some(Err(e)) => {
+                error!(error = %e, "Error reading request");
                 break;
             }
-        }
+        };
 
-        let request: ParsedRequest = match bincode::deserialize(&buffer) {
-            Ok(req) => req,
-            Err(e) => {
-                eprintln!(
-                    "Failed to deserialize request (connection {}): {}",
-                    conn_id, e
-                );
-                break;
+        // Each request gets its own channel back, the front end may have several connections
+        // to this worker waiting on responses at once
+        let (responder, mut rx_response) = unbounded_channel();
+        // The body the front end held back goes to Python once the app asks for it
+        let (mut body_sender, deferred_body) = match request.body_deferred {
+            true => {
+                let (sender, receiver) = bounded(1);
+                (Some(sender), Some(receiver))
             }
+            false => (None, None),
         };
 
         // Send request to python process
-        if let Err(e) = tx_request.send(Connection {
+        match tx_request.try_send(Connection {
             id: conn_id,
-            request,
+            request: PythonRequest {
+                request,
+                capabilities,
+                responder,
+                deferred_body,
+            },
         }) {
-            eprintln!(
-                "Failed to send request to Python (connection {}): {}",
-                conn_id, e
-            );
-            break;
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                warn!("Request queue is full, rejecting");
+
+                let mut start = HttpResponseStart::new("http.response.start", 503);
+                start.add_header(b"retry-after", RETRY_AFTER_SECONDS.as_bytes());
+                let body = HttpResponseBody::new(Vec::new(), false);
+
+                if framed
+                    .send(ASGIMessages::HttpResponseStart(start))
+                    .await
+                    .is_err()
+                    || framed
+                        .send(ASGIMessages::HttpResponseBody(body))
+                        .await
+                        .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Failed to send request to Python: Python stopped");
+                break;
+            }
         }
 
-        for i in 0..2 {
-            match rx_response.recv() {
-                Ok(response) => {
-                    let payload = bincode::serialize(&response).unwrap();
-                    let payload_len = payload.len() as u32;
-
-                    // Try to send both length and payload
-                    let send_result = async {
-                        stream.write_all(&payload_len.to_be_bytes()).await?;
-                        stream.write_all(&payload).await?;
-                        stream.flush().await?;
-                        Ok::<_, std::io::Error>(())
+        let mut started = false;
+        // The response ends with trailers rather than with its last body part
+        let mut trailers = false;
+        // Body parts held back until the last one when the front end can't take a stream
+        let mut buffered = Vec::new();
+
+        loop {
+            match rx_response.recv().await {
+                Some(ASGIMessages::ReadBody) => {
+                    let Some(body_sender) = body_sender.take() else {
+                        error!("The app asked for a request body that isn't coming");
+                        return;
+                    };
+                    if let Err(e) = framed.send(ASGIMessages::ReadBody).await {
+                        error!(error = %e, "Error asking for the request body");
+                        return;
                     }
-                    .await;
-
-                    if let Err(e) = send_result {
-                        if e.kind() == std::io::ErrorKind::BrokenPipe {
-                            println!(
-                                "Client disconnected while sending response (connection {})",
-                                conn_id
-                            );
-                        } else {
-                            eprintln!("Error sending response (connection {}): {}", conn_id, e);
+
+                    match framed.next().await {
+                        Some(Ok(WorkerRequest::Body(body))) => {
+                            let _ = body_sender.send(body);
+                        }
+                        Some(Ok(_)) => {
+                            error!("Expected the request body");
+                            return;
+                        }
+                        Some(Err(e)) => {
+                            error!(error = %e, "Error reading the request body");
+                            return;
+                        }
+                        None => {
+                            debug!("Client disconnected before sending the request body");
+                            return;
                         }
-                        break;
                     }
+                }
+                Some(mut response) => {
+                    let in_order = match &response {
+                        ASGIMessages::HttpResponseEarlyHint(_) => !started,
+                        ASGIMessages::HttpResponseStart(start) => {
+                            trailers = start.trailers;
+                            !std::mem::replace(&mut started, true)
+                        }
+                        ASGIMessages::HttpResponseBody(_) | ASGIMessages::HttpResponseFile(_) => {
+                            started
+                        }
+                        ASGIMessages::HttpResponseTrailers(_) => started && trailers,
+                        _ => false,
+                    };
+                    // Whatever the app sends next can't make a valid response, dropping the
+                    // connection is how the front end learns it's broken
+                    if !in_order {
+                        error!("Unexpected message order");
+                        return;
+                    }
+
+                    let last = match &mut response {
+                        ASGIMessages::HttpResponseBody(body) if !streaming => {
+                            buffered.append(&mut body.body);
+                            if body.more_body {
+                                continue;
+                            }
+                            body.body = std::mem::take(&mut buffered);
+                            !trailers
+                        }
+                        ASGIMessages::HttpResponseBody(body) => !body.more_body && !trailers,
+                        ASGIMessages::HttpResponseFile(file) => !file.more_body && !trailers,
+                        ASGIMessages::HttpResponseTrailers(trailers) => !trailers.more_trailers,
+                        _ => false,
+                    };
 
-                    match (i, &response) {
-                        (0, ASGIMessages::HttpResponseStart(_)) => continue,
-                        (1, ASGIMessages::HttpResponseBody(_)) => (),
-                        _ => {
-                            eprintln!("Unexpected message order (connection {})", conn_id);
-                            break;
+                    if let Err(e) = framed.send(response).await {
+                        match e {
+                            CodecError::Io(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                                warn!("Client disconnected while sending response");
+                            }
+                            e => {
+                                error!(error = %e, "Error sending response")
+                            }
                         }
+                        return;
+                    }
+
+                    if last {
+                        break;
                     }
                 }
-                Err(e) => {
-                    eprintln!(
-                        "Error receiving response from Python (connection {}): {}",
-                        conn_id, e
-                    );
-                    break;
+                None => {
+                    error!("Python stopped before completing the response");
+                    return;
                 }
             }
         }
     }
 
-    println!("Connection {} closed", conn_id);
+    debug!("Connection closed");
 }
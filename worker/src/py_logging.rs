@@ -0,0 +1,95 @@
+use pyo3::{
+    ffi::c_str,
+    pyfunction,
+    types::{PyAnyMethods, PyModule, PyModuleMethods},
+    wrap_pyfunction, PyErr, PyResult, Python,
+};
+use tracing::{debug, error, info, level_filters::LevelFilter, trace, warn};
+
+/// Handler installed on the root logger, everything it receives is passed on to `_emit`.
+const HANDLER: &std::ffi::CStr = c_str!(
+    r#"
+import logging
+
+
+class FerricornHandler(logging.Handler):
+    def emit(self, record):
+        try:
+            exc_text = None
+            if record.exc_info:
+                exc_text = logging.Formatter().formatException(record.exc_info)
+            elif record.exc_text:
+                exc_text = record.exc_text
+            _emit(record.levelno, record.name, record.getMessage(), exc_text)
+        except Exception:
+            self.handleError(record)
+
+
+def install(level):
+    root = logging.getLogger()
+    root.addHandler(FerricornHandler())
+    root.setLevel(level)
+"#
+);
+
+// Levels of Python's logging module
+const PY_CRITICAL: u32 = 50;
+const PY_ERROR: u32 = 40;
+const PY_WARNING: u32 = 30;
+const PY_INFO: u32 = 20;
+const PY_DEBUG: u32 = 10;
+
+/// Sends the app's `logging` records at `level` or above to the worker's own logs, before
+/// the app is imported so that it can still configure logging its own way.
+pub fn install(py: Python<'_>, level: LevelFilter) -> PyResult<()> {
+    let module = PyModule::from_code(
+        py,
+        HANDLER,
+        c_str!("ferricorn_logging.py"),
+        c_str!("ferricorn_logging"),
+    )?;
+    module.add("_emit", wrap_pyfunction!(emit, &module)?)?;
+
+    let py_level = match level {
+        LevelFilter::OFF => PY_CRITICAL + 1,
+        LevelFilter::ERROR => PY_ERROR,
+        LevelFilter::WARN => PY_WARNING,
+        LevelFilter::INFO => PY_INFO,
+        // Python has nothing below DEBUG, a level of 0 would mean "inherit" on the root
+        _ => PY_DEBUG,
+    };
+    module.getattr("install")?.call1((py_level,))?;
+
+    Ok(())
+}
+
+/// Logs an exception the app let through, with its traceback.
+pub fn log_exception(py: Python<'_>, err: &PyErr, message: &str) {
+    let traceback = match format_exception(py, err) {
+        Ok(traceback) => traceback,
+        Err(_) => err.to_string(),
+    };
+
+    error!(exc_info = traceback.trim_end(), "{}", message);
+}
+
+fn format_exception(py: Python<'_>, err: &PyErr) -> PyResult<String> {
+    let lines = py
+        .import("traceback")?
+        .call_method1("format_exception", (err.value(py),))?
+        .extract::<Vec<String>>()?;
+
+    Ok(lines.concat())
+}
+
+#[pyfunction]
+#[pyo3(signature = (level, logger, message, exc_info))]
+fn emit(level: u32, logger: &str, message: &str, exc_info: Option<&str>) {
+    match level {
+        PY_ERROR.. => error!(logger, exc_info, "{}", message),
+        PY_WARNING.. => warn!(logger, exc_info, "{}", message),
+        PY_INFO.. => info!(logger, exc_info, "{}", message),
+        PY_DEBUG.. => debug!(logger, exc_info, "{}", message),
+        _ => trace!(logger, exc_info, "{}", message),
+    }
+}
@@ -1,21 +1,122 @@
 use std::{
-    io::Write as _,
-    os::unix::net::UnixStream,
-    sync::{mpsc, Arc},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
 };
 
-use crossbeam_channel::Sender;
 use pyo3::{
+    ffi::c_str,
     types::{
-        PyAnyMethods, PyBytes, PyCFunction, PyDict, PyList, PyListMethods, PyModule, PyString,
+        PyAnyMethods, PyBytes, PyCFunction, PyDict, PyDictMethods, PyList, PyListMethods, PyModule,
         PyTuple,
     },
-    Bound, IntoPyObjectExt, Py, PyAny, PyResult, Python,
+    Bound, Py, PyAny, PyResult, Python,
 };
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info_span, level_filters::LevelFilter, trace, warn, Span};
+
+use crate::lifespan::{self, Startup};
+use crate::py_logging;
+
+use messages::types::{
+    ASGIMessages, Capabilities, HttpResponseBody, HttpResponseEarlyHint, HttpResponseFile,
+    HttpResponseStart, HttpResponseTrailers, ParsedRequest,
+};
+use observability::telemetry;
+
+/// A request for the app along with where to send the messages it produces.
+pub struct PythonRequest {
+    pub request: ParsedRequest,
+    /// What the front end can take, the app is only offered the matching extensions
+    pub capabilities: Capabilities,
+    pub responder: UnboundedSender<ASGIMessages>,
+    /// Delivers the body when the front end held it back, once the app asked for it
+    pub deferred_body: Option<crossbeam_channel::Receiver<Vec<u8>>>,
+}
 
-use messages::types::{ASGIMessages, HttpResponseBody, HttpResponseStart, ParsedRequest};
-use tokio::io::AsyncWriteExt;
+enum RequestBody {
+    Ready(Vec<u8>),
+    Deferred(crossbeam_channel::Receiver<Vec<u8>>),
+    /// Asked for, with the futures of the `receive()` calls waiting for it
+    Waiting(Vec<Py<PyAny>>),
+    /// The front end went away before sending the body
+    Gone,
+}
+
+impl RequestBody {
+    /// The `receive()` event once the body is no longer on its way.
+    fn event<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let event = PyDict::new(py);
+        match self {
+            RequestBody::Ready(body) => {
+                event.set_item("type", "http.request")?;
+                event.set_item("body", PyBytes::new(py, body))?;
+                event.set_item("more_body", false)?;
+            }
+            _ => event.set_item("type", "http.disconnect")?,
+        }
+
+        Ok(event)
+    }
+}
+
+/// Waits for a held back body off the Python thread, so that the app's event loop keeps
+/// running meanwhile, then resolves the `receive()` calls waiting for it on that loop.
+fn wait_for_body(
+    deferred_body: crossbeam_channel::Receiver<Vec<u8>>,
+    request_body: Arc<Mutex<RequestBody>>,
+    event_loop: Py<PyAny>,
+) {
+    let span = Span::current();
+
+    thread::spawn(move || {
+        let _entered = span.enter();
+        let received = match deferred_body.recv() {
+            Ok(body) => RequestBody::Ready(body),
+            Err(_) => RequestBody::Gone,
+        };
+
+        Python::with_gil(|py| {
+            let mut request_body = request_body.lock().unwrap();
+            let event = received.event(py);
+            let waiting = std::mem::replace(&mut *request_body, received);
+
+            let (RequestBody::Waiting(futures), Ok(event)) = (waiting, event) else {
+                return;
+            };
+            for future in futures {
+                // The app may have stopped waiting, e.g. it cancelled receive() on a timeout
+                let resolve = py
+                    .eval(
+                        c_str!("lambda future, event: future.done() or future.set_result(event)"),
+                        None,
+                        None,
+                    )
+                    .and_then(|resolve| {
+                        event_loop.call_method1(
+                            py,
+                            "call_soon_threadsafe",
+                            (resolve, future, &event),
+                        )
+                    });
+                if let Err(err) = resolve {
+                    debug!(error = %err, "Failed to hand the request body to the app");
+                }
+            }
+        });
+    });
+}
+
+/// What the Python thread is handed.
+pub enum Job {
+    Request(Box<PythonRequest>),
+    /// Runs the app's lifespan shutdown and stops the thread, then answers on the channel
+    Shutdown(mpsc::Sender<()>),
+}
 
 pub struct PythonProcess;
 
@@ -23,36 +124,96 @@ impl PythonProcess {
     pub fn start(
         app_module: String,
         asgi_attr: String,
-        asgi_sender: Sender<ASGIMessages>,
-    ) -> Result<mpsc::Sender<ParsedRequest>, Box<dyn std::error::Error>> {
-        let (tx, rx) = mpsc::channel::<ParsedRequest>();
+        log_level: LevelFilter,
+        started: Arc<AtomicBool>,
+    ) -> Result<mpsc::SyncSender<Job>, Box<dyn std::error::Error>> {
+        // Requests are handed over one at a time, the queue in front of Python is the
+        // worker's bounded request channel
+        let (tx, rx) = mpsc::sync_channel::<Job>(0);
+
+        let span = Span::current();
 
         thread::spawn(move || {
-            dbg!(" starting python");
+            let _entered = span.enter();
+            debug!("Starting Python");
+
             Python::with_gil(|py| {
-                dbg!(" python started");
-                let app_module_arc = Arc::new(app_module);
-                let asgi_attr_arc = Arc::new(asgi_attr);
+                if let Err(err) = py_logging::install(py, log_level) {
+                    py_logging::log_exception(py, &err, "Failed to forward the app's logs");
+                }
+
+                // One loop for the lifespan and every request, so that what the app sets up at
+                // startup can be used while serving
+                let asyncio = PyModule::import(py, "asyncio").unwrap();
+                let event_loop = asyncio.call_method0("new_event_loop").unwrap();
+                asyncio
+                    .call_method1("set_event_loop", (&event_loop,))
+                    .unwrap();
+
+                let asgi_app = match load_app(py, &app_module, &asgi_attr) {
+                    Ok(asgi_app) => asgi_app,
+                    Err(err) => {
+                        py_logging::log_exception(py, &err, "Failed to load the app");
+                        process::exit(1)
+                    }
+                };
+
+                let lifespan_state = PyDict::new(py);
+                let mut lifespan =
+                    match lifespan::startup(py, &asgi_app, &event_loop, &lifespan_state) {
+                        Ok(Startup::Complete(lifespan)) => Some(lifespan),
+                        Ok(Startup::Unsupported) => None,
+                        Ok(Startup::Failed(message)) => {
+                            error!(reason = message, "The app's lifespan startup failed");
+                            process::exit(1)
+                        }
+                        Err(err) => {
+                            py_logging::log_exception(
+                                py,
+                                &err,
+                                "Exception in the lifespan startup",
+                            );
+                            process::exit(1)
+                        }
+                    };
+                started.store(true, Ordering::Relaxed);
+
+                loop {
+                    let PythonRequest {
+                        request: request_data,
+                        capabilities,
+                        responder: asgi_sender,
+                        deferred_body,
+                    } = match rx.recv() {
+                        Ok(Job::Request(request)) => *request,
+                        Ok(Job::Shutdown(done)) => {
+                            if let Some(lifespan) = lifespan.take() {
+                                if let Err(err) = lifespan.shutdown(py, &event_loop) {
+                                    py_logging::log_exception(
+                                        py,
+                                        &err,
+                                        "Exception in the lifespan shutdown",
+                                    );
+                                }
+                            }
+                            let _ = done.send(());
+                            break;
+                        }
+                        Err(_) => break,
+                    };
+
+                    // Anything the app logs while handling the request is tagged with its id
+                    let request_span = info_span!(
+                        target: telemetry::TARGET,
+                        "request",
+                        otel.name = "ASGI app",
+                        id = %request_data.request_id
+                    );
+                    telemetry::set_parent(&request_span, &request_data.trace_context);
+                    let _request_entered = request_span.enter();
 
-                while let Ok(request_data) = rx.recv() {
                     // Ensure Python's signal handlers are set up
                     let _ = py.check_signals();
-                    let app_module_arc = Arc::clone(&app_module_arc);
-                    let asgi_attr_arc = Arc::clone(&asgi_attr_arc);
-
-                    let sys_path = py.import("sys").unwrap().getattr("path").unwrap();
-                    let _ = sys_path
-                        .call_method1(
-                            "append",
-                            (format!(
-                                "{}/py",
-                                std::env::current_dir().unwrap().to_string_lossy()
-                            ),),
-                        )
-                        .unwrap();
-
-                    let app_module =
-                        PyModule::import(py, PyString::new(py, &app_module_arc)).unwrap();
 
                     let scope = PyDict::new(py);
                     let _ = scope.set_item("type", "http");
@@ -64,53 +225,100 @@ impl PythonProcess {
                     let _ = scope.set_item("asgi", asgi);
                     let _ = scope.set_item("http_version", "1.1");
                     let _ = scope.set_item("method", request_data.method.to_string());
-                    let _ = scope.set_item("scheme", request_data.uri.scheme());
-                    let _ = scope.set_item("path", request_data.uri.path());
-                    let _ = scope.set_item("raw_path", request_data.uri.path().as_bytes());
-                    let _ = scope.set_item("query_string", request_data.uri.query_string());
-                    let _ = scope.set_item("root_path", "");
+                    let _ = scope.set_item("scheme", request_data.uri.scheme().unwrap_or("http"));
+                    // Like uvicorn, path and raw_path include the root_path the app is mounted at
+                    let root_path = &request_data.root_path;
+                    let raw_path = [root_path.as_bytes(), request_data.uri.raw_path()].concat();
+                    let _ =
+                        scope.set_item("path", format!("{}{}", root_path, request_data.uri.path()));
+                    let _ = scope.set_item("raw_path", PyBytes::new(py, &raw_path));
+                    let _ = scope.set_item(
+                        "query_string",
+                        PyBytes::new(py, request_data.uri.query_string()),
+                    );
+                    let _ = scope.set_item("root_path", root_path);
 
                     let scope_headers = PyList::empty(py);
 
                     for (name, val) in request_data.headers {
                         let _ = scope_headers
-                            .append(PyTuple::new(py, [name.as_bytes(), val.as_bytes()]).unwrap());
+                            .append(PyTuple::new(py, [name.as_slice(), val.as_slice()]).unwrap());
                     }
 
                     let _ = scope.set_item("headers", scope_headers);
 
+                    let extensions = PyDict::new(py);
+                    if capabilities.contains(Capabilities::FILES) {
+                        let _ = extensions.set_item("http.response.pathsend", PyDict::new(py));
+                        let _ = extensions.set_item("http.response.zerocopysend", PyDict::new(py));
+                    }
+                    let trailers_allowed = capabilities.contains(Capabilities::TRAILERS);
+                    if trailers_allowed {
+                        let _ = extensions.set_item("http.response.trailers", PyDict::new(py));
+                    }
+                    let early_hints = capabilities.contains(Capabilities::EARLY_HINTS);
+                    if early_hints {
+                        let _ = extensions.set_item("http.response.early_hint", PyDict::new(py));
+                    }
+                    if !extensions.is_empty() {
+                        let _ = scope.set_item("extensions", extensions);
+                    }
+
+                    let _ = scope.set_item("state", lifespan_state.copy().unwrap());
                     let _ = scope.set_item("client", "");
                     let _ = scope.set_item("server", "");
 
-                    let clone_body = request_data.body.clone();
-                    let receive_callback = move |args: &Bound<'_, PyTuple>,
+                    // A body held back by the front end is fetched on the first receive(),
+                    // which is when the client gets its 100 Continue
+                    let request_body = Arc::new(Mutex::new(match deferred_body {
+                        Some(deferred_body) => RequestBody::Deferred(deferred_body),
+                        None => RequestBody::Ready(request_data.body.clone()),
+                    }));
+                    let body_requests = asgi_sender.clone();
+                    let receive_callback = move |_args: &Bound<'_, PyTuple>,
                                                  _kwargs: Option<&Bound<'_, PyDict>>|
                           -> PyResult<Py<PyAny>> {
                         Python::with_gil(|py| {
-                            dbg!(args);
-                            dbg!(_kwargs);
-
-                            let callback_body = clone_body.clone();
-                            let body = PyBytes::new(py, &callback_body);
-
-                            let event = PyDict::new(py);
-
-                            event.set_item("type", "http.request").unwrap();
-                            event.set_item("body", body).unwrap();
-                            event.set_item("more_body", false).unwrap();
-
-                            // Create and return a resolved future
-                            let asyncio = py.import("asyncio")?;
-                            let future = asyncio.call_method0("Future")?;
-                            future.call_method1("set_result", (event,))?;
+                            let event_loop =
+                                py.import("asyncio")?.call_method0("get_running_loop")?;
+                            let future = event_loop.call_method0("create_future")?;
+
+                            let mut state = request_body.lock().unwrap();
+                            match std::mem::replace(&mut *state, RequestBody::Gone) {
+                                RequestBody::Deferred(deferred_body) => {
+                                    *state = RequestBody::Waiting(vec![future.clone().unbind()]);
+                                    let _ = body_requests.send(ASGIMessages::ReadBody);
+                                    wait_for_body(
+                                        deferred_body,
+                                        Arc::clone(&request_body),
+                                        event_loop.unbind(),
+                                    );
+                                }
+                                RequestBody::Waiting(mut futures) => {
+                                    futures.push(future.clone().unbind());
+                                    *state = RequestBody::Waiting(futures);
+                                }
+                                body => {
+                                    let event = body.event(py);
+                                    *state = body;
+                                    future.call_method1("set_result", (event?,))?;
+                                }
+                            }
 
-                            Ok(future.into())
+                            Ok(future.unbind())
                         })
                     };
 
                     // let tx_for_send = Arc::clone(&data.callback);
 
                     let clone = asgi_sender.clone();
+                    let response_started = Arc::new(AtomicBool::new(false));
+                    let response_complete = Arc::new(AtomicBool::new(false));
+                    // Set while the app still owes the trailers it announced
+                    let trailers_pending = Arc::new(AtomicBool::new(false));
+                    let started = Arc::clone(&response_started);
+                    let complete = Arc::clone(&response_complete);
+                    let pending = Arc::clone(&trailers_pending);
                     let send_callback = move |args: &Bound<'_, PyTuple>,
                                               _kwargs: Option<&Bound<'_, PyDict>>|
                           -> PyResult<Py<PyAny>> {
@@ -120,16 +328,53 @@ impl PythonProcess {
                             let data_type = data_type_result.extract::<String>().unwrap();
 
                             let data_type_ref = data_type.as_str();
-                            dbg!(&data_type_ref);
+                            trace!(message_type = data_type_ref, "Message from the app");
 
                             match data_type_ref {
+                                "http.response.early_hint"
+                                    if early_hints && !started.load(Ordering::Relaxed) =>
+                                {
+                                    let mut links = Vec::new();
+                                    if let Ok(values) = data.get_item("links") {
+                                        for link in values.try_iter()? {
+                                            let link = link?;
+                                            links.push(match link.extract::<String>() {
+                                                Ok(link) => link.into_bytes(),
+                                                Err(_) => link.extract::<Vec<u8>>()?,
+                                            });
+                                        }
+                                    }
+
+                                    let hint = HttpResponseEarlyHint::new(links);
+                                    let _ = clone.send(ASGIMessages::HttpResponseEarlyHint(hint));
+                                }
                                 "http.response.start" => {
-                                    let start = HttpResponseStart::new(
+                                    let mut start = HttpResponseStart::new(
                                         &data_type,
                                         data.get_item("status").unwrap().extract::<u16>().unwrap(),
                                     );
 
-                                    clone.send(ASGIMessages::HttpResponseStart(start));
+                                    if let Ok(headers) = data.get_item("headers") {
+                                        for header in headers.try_iter()? {
+                                            let (name, value) =
+                                                header?.extract::<(Vec<u8>, Vec<u8>)>()?;
+                                            start.add_header(&name, &value);
+                                        }
+                                    }
+
+                                    let trailers = match data.get_item("trailers") {
+                                        Ok(trailers) => trailers.is_truthy()?,
+                                        Err(_) => false,
+                                    };
+                                    if trailers && !trailers_allowed {
+                                        warn!("Ignoring trailers, the front end can't send them");
+                                    }
+                                    start.set_trailers(trailers && trailers_allowed);
+                                    pending.store(start.trailers, Ordering::Relaxed);
+
+                                    // The front end may be gone already, nothing to do then
+                                    let _ = clone.send(ASGIMessages::HttpResponseStart(start));
+                                    started.store(true, Ordering::Relaxed);
                                     // request_data
                                     // j
                                     //     .callback
@@ -137,19 +382,69 @@ impl PythonProcess {
                                     //     .unwrap();
                                 }
                                 "http.response.body" => {
-                                    let body_bytes = data.get_item("body").unwrap();
-                                    let body_vec = body_bytes.extract::<Vec<u8>>();
-
-                                    let body = HttpResponseBody::new(body_vec.unwrap());
-                                    clone.send(ASGIMessages::HttpResponseBody(body)).unwrap();
+                                    // Both keys are optional, an empty last message by default
+                                    let body = match data.get_item("body") {
+                                        Ok(body) => body.extract::<Vec<u8>>()?,
+                                        Err(_) => Vec::new(),
+                                    };
+                                    let more_body = match data.get_item("more_body") {
+                                        Ok(more_body) => more_body.is_truthy()?,
+                                        Err(_) => false,
+                                    };
+
+                                    let body = HttpResponseBody::new(body, more_body);
+                                    let _ = clone.send(ASGIMessages::HttpResponseBody(body));
+                                    if !more_body {
+                                        complete.store(true, Ordering::Relaxed);
+                                    }
 
                                     // request_data
                                     //     .callback
                                     //     .send(ASGIMessages::HttpResponseBody(body))
                                     //     .unwrap();
                                 }
+                                "http.response.pathsend" | "http.response.zerocopysend" => {
+                                    let message = match data_type_ref {
+                                        "http.response.pathsend" => pathsend(&data)?,
+                                        _ => zerocopysend(py, &data)?,
+                                    };
+                                    let last = match &message {
+                                        ASGIMessages::HttpResponseBody(body) => !body.more_body,
+                                        ASGIMessages::HttpResponseFile(file) => !file.more_body,
+                                        _ => true,
+                                    };
+
+                                    let _ = clone.send(message);
+                                    if last {
+                                        complete.store(true, Ordering::Relaxed);
+                                    }
+                                }
+                                "http.response.trailers" if pending.load(Ordering::Relaxed) => {
+                                    let more_trailers = match data.get_item("more_trailers") {
+                                        Ok(more_trailers) => more_trailers.is_truthy()?,
+                                        Err(_) => false,
+                                    };
+                                    let mut trailers = HttpResponseTrailers::new(more_trailers);
+
+                                    if let Ok(headers) = data.get_item("headers") {
+                                        for header in headers.try_iter()? {
+                                            let (name, value) =
+                                                header?.extract::<(Vec<u8>, Vec<u8>)>()?;
+                                            trailers.add_header(&name, &value);
+                                        }
+                                    }
+
+                                    let _ =
+                                        clone.send(ASGIMessages::HttpResponseTrailers(trailers));
+                                    if !more_trailers {
+                                        pending.store(false, Ordering::Relaxed);
+                                    }
+                                }
                                 _ => {
-                                    dbg!(data);
+                                    warn!(
+                                        message_type = data_type_ref,
+                                        "Ignoring unsupported message from the app"
+                                    );
                                 }
                             };
 
@@ -166,35 +461,40 @@ impl PythonProcess {
                         PyCFunction::new_closure(py, None, None, receive_callback).unwrap();
                     let send = PyCFunction::new_closure(py, None, None, send_callback).unwrap();
 
-                    dbg!(&scope);
+                    debug!(
+                        method = %request_data.method,
+                        path = request_data.uri.path(),
+                        "Calling the app"
+                    );
                     let scope_any: Py<PyAny> = scope.into();
                     let receive_any: Py<PyAny> = receive.into();
                     let send_any: Py<PyAny> = send.into();
 
                     let asgi_args = PyTuple::new(py, &[scope_any, receive_any, send_any]).unwrap();
 
-                    // Import asyncio to run the event loop
-                    let asyncio = PyModule::import(py, "asyncio").unwrap();
-                    // Create and set new event loop
-
-                    let event_loop = asyncio.call_method0("new_event_loop").unwrap();
-                    asyncio
-                        .call_method1("set_event_loop", (&event_loop,))
-                        .unwrap();
-
-                    let asgi_app = app_module
-                        .getattr(PyString::new(py, &asgi_attr_arc))
-                        .unwrap();
-
                     // Create the coroutine to call the FastAPI app
                     let coroutine = asgi_app.call1(asgi_args).unwrap();
 
-                    // Run the coroutine until it completes
-                    event_loop
-                        .call_method1("run_until_complete", (coroutine,))
-                        .unwrap();
+                    // Run the coroutine until it completes, an exception in the app must not
+                    // take the whole worker down
+                    if let Err(err) = event_loop.call_method1("run_until_complete", (coroutine,)) {
+                        py_logging::log_exception(py, &err, "Exception in ASGI application");
+                    }
 
-                    event_loop.call_method0("close").unwrap();
+                    // The front end waits for a complete response even when the app failed or
+                    // returned without one
+                    if !response_started.load(Ordering::Relaxed) {
+                        let start = HttpResponseStart::new("http.response.start", 500);
+                        let _ = asgi_sender.send(ASGIMessages::HttpResponseStart(start));
+                    }
+                    if !response_complete.load(Ordering::Relaxed) {
+                        let body = HttpResponseBody::new(Vec::new(), false);
+                        let _ = asgi_sender.send(ASGIMessages::HttpResponseBody(body));
+                    }
+                    if trailers_pending.load(Ordering::Relaxed) {
+                        let trailers = HttpResponseTrailers::new(false);
+                        let _ = asgi_sender.send(ASGIMessages::HttpResponseTrailers(trailers));
+                    }
                 }
             });
         });
@@ -202,3 +502,84 @@ impl PythonProcess {
         Ok(tx)
     }
 }
+
+/// Imports the app, with the `py` directory of the working directory importable too.
+fn load_app<'py>(py: Python<'py>, module: &str, attr: &str) -> PyResult<Bound<'py, PyAny>> {
+    let sys_path = py.import("sys")?.getattr("path")?;
+    sys_path.call_method1(
+        "append",
+        (format!("{}/py", std::env::current_dir()?.to_string_lossy()),),
+    )?;
+
+    PyModule::import(py, module)?.getattr(attr)
+}
+
+/// `http.response.pathsend`: the front end sends the whole file at `path`.
+fn pathsend(data: &Bound<'_, PyAny>) -> PyResult<ASGIMessages> {
+    let path = data.get_item("path")?.extract::<PathBuf>()?;
+    let path = std::path::absolute(path)?;
+
+    Ok(ASGIMessages::HttpResponseFile(HttpResponseFile::new(
+        path, 0, None, false,
+    )))
+}
+
+/// `http.response.zerocopysend`: the front end reopens the file behind the descriptor by
+/// path, the descriptor itself can't leave this process. Files without a path, deleted or
+/// not on disk, are read here and sent as a regular body.
+fn zerocopysend(py: Python<'_>, data: &Bound<'_, PyAny>) -> PyResult<ASGIMessages> {
+    let os = py.import("os")?;
+    let file = data.get_item("file")?;
+    let fd = match file.hasattr("fileno")? {
+        true => file.call_method0("fileno")?.extract::<i32>()?,
+        false => file.extract::<i32>()?,
+    };
+    let optional = |key| -> PyResult<Option<u64>> {
+        match data.get_item(key) {
+            Ok(value) if !value.is_none() => Ok(Some(value.extract::<u64>()?)),
+            _ => Ok(None),
+        }
+    };
+    let more_body = match data.get_item("more_body") {
+        Ok(more_body) => more_body.is_truthy()?,
+        Err(_) => false,
+    };
+
+    let size = os
+        .call_method1("fstat", (fd,))?
+        .getattr("st_size")?
+        .extract::<u64>()?;
+    // Without an offset the file is sent from its current position, which then moves past
+    // what was sent
+    let (offset, advance) = match optional("offset")? {
+        Some(offset) => (offset, false),
+        None => {
+            let position = os.call_method1("lseek", (fd, 0, os.getattr("SEEK_CUR")?))?;
+            (position.extract::<u64>()?, true)
+        }
+    };
+    let count = optional("count")?
+        .unwrap_or(u64::MAX)
+        .min(size.saturating_sub(offset));
+    if advance {
+        os.call_method1("lseek", (fd, offset + count, os.getattr("SEEK_SET")?))?;
+    }
+
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd))
+        .ok()
+        .filter(|path| path.is_file());
+    let message = match path {
+        Some(path) => ASGIMessages::HttpResponseFile(HttpResponseFile::new(
+            path,
+            offset,
+            Some(count),
+            more_body,
+        )),
+        None => {
+            let body = os.call_method1("pread", (fd, count, offset))?;
+            ASGIMessages::HttpResponseBody(HttpResponseBody::new(body.extract()?, more_body))
+        }
+    };
+
+    Ok(message)
+}